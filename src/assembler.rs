@@ -1,14 +1,35 @@
 use std::collections::{btree_map::Entry, BTreeMap};
 
 use anyhow::{bail, ensure};
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
 
 use crate::{
+    extensions::Extensions,
     fields::Bits,
     instructions::{Assembler, Instruction},
 };
 
 pub fn assemble(base_addr: u32, source: &str) -> anyhow::Result<(Vec<u8>, BTreeMap<String, u32>)> {
-    let instructions = Instruction::parse(source)?;
+    assemble_with(&Extensions::default(), base_addr, source)
+}
+
+pub fn assemble_with(
+    extensions: &Extensions,
+    base_addr: u32,
+    source: &str,
+) -> anyhow::Result<(Vec<u8>, BTreeMap<String, u32>)> {
+    let (regions, labels) = assemble_regions_with(extensions, base_addr, source)?;
+    Ok((flatten(base_addr, &regions)?, labels))
+}
+
+/// Assembles like `assemble_with`, keeping the regions started by `.org` apart.
+pub fn assemble_regions_with(
+    extensions: &Extensions,
+    base_addr: u32,
+    source: &str,
+) -> anyhow::Result<(Vec<Region>, BTreeMap<String, u32>)> {
+    let instructions = Instruction::parse_with(source, extensions)?;
 
     let mut label_assembler = LabelAssembler::new(base_addr);
     label_assembler.assemble(&instructions)?;
@@ -16,7 +37,60 @@ pub fn assemble(base_addr: u32, source: &str) -> anyhow::Result<(Vec<u8>, BTreeM
     let mut output_assembler = OutputAssembler::new(base_addr, label_assembler.labels);
     output_assembler.assemble(&instructions)?;
 
-    Ok((output_assembler.output, output_assembler.labels))
+    let mut regions = output_assembler.regions;
+    regions.retain(|region| !region.code.is_empty());
+    Ok((regions, output_assembler.labels))
+}
+
+/// A contiguous run of code starting at `address`.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Region {
+    pub address: u32,
+    #[serde_as(as = "serde_with::hex::Hex")]
+    pub code: Vec<u8>,
+}
+
+impl Region {
+    pub fn end(&self) -> u64 {
+        self.address as u64 + self.code.len() as u64
+    }
+
+    /// Decodes each whole word of the region, along with its address.
+    pub fn disassemble<'a>(
+        &'a self,
+        extensions: &'a Extensions,
+    ) -> impl Iterator<Item = (u32, u32, Instruction)> + 'a {
+        self.code.chunks_exact(4).enumerate().map(|(index, word)| {
+            let address = self.address + 4 * index as u32;
+            let word = u32::from_be_bytes(word.try_into().unwrap());
+            (
+                address,
+                word,
+                Instruction::decode_with(word, address, extensions),
+            )
+        })
+    }
+}
+
+/// Lays out `regions` from `base_addr` on, filling the gaps with zeros.
+pub fn flatten(base_addr: u32, regions: &[Region]) -> anyhow::Result<Vec<u8>> {
+    let mut code = vec![];
+    for region in regions {
+        ensure!(
+            region.address >= base_addr,
+            "Region at {:#x} starts before the base address {:#x}",
+            region.address,
+            base_addr
+        );
+        let start = (region.address - base_addr) as usize;
+        let end = start + region.code.len();
+        if code.len() < end {
+            code.resize(end, 0);
+        }
+        code[start..end].copy_from_slice(&region.code);
+    }
+    Ok(code)
 }
 
 pub fn assemble_template(
@@ -24,27 +98,41 @@ pub fn assemble_template(
     template: &str,
     parameters: &BTreeMap<String, u64>,
 ) -> anyhow::Result<(Vec<u8>, BTreeMap<String, u32>)> {
+    assemble_template_with(&Extensions::default(), base_addr, template, parameters)
+}
+
+pub fn assemble_template_with(
+    extensions: &Extensions,
+    base_addr: u32,
+    template: &str,
+    parameters: &BTreeMap<String, u64>,
+) -> anyhow::Result<(Vec<u8>, BTreeMap<String, u32>)> {
+    let source = render_template(template, parameters)?;
+    let (code, labels) = assemble_with(extensions, base_addr, &source)?;
+    Ok((code, labels))
+}
+
+pub fn render_template(
+    template: &str,
+    parameters: &BTreeMap<String, u64>,
+) -> anyhow::Result<String> {
     let mut ctx = tera::Context::new();
     for (k, v) in parameters.iter() {
         ctx.insert(k, v);
     }
-    let source = tera::Tera::one_off(template, &ctx, false)?;
-    let (code, labels) = assemble(base_addr, &source)?;
-    Ok((code, labels))
+    Ok(tera::Tera::one_off(template, &ctx, false)?)
 }
 
 pub struct LabelAssembler {
-    base_addr: u32,
-    labels: BTreeMap<String, u32>,
-    offset: u32,
+    address: u32,
+    pub(crate) labels: BTreeMap<String, u32>,
 }
 
 impl LabelAssembler {
     pub fn new(base_addr: u32) -> Self {
         Self {
-            base_addr,
+            address: base_addr,
             labels: Default::default(),
-            offset: Default::default(),
         }
     }
 }
@@ -53,7 +141,7 @@ impl Assembler for LabelAssembler {
     type Err = anyhow::Error;
 
     fn current_address(&self) -> u32 {
-        self.base_addr + self.offset
+        self.address
     }
 
     fn label(&mut self, name: &str, address: u32) -> Result<(), Self::Err> {
@@ -66,27 +154,45 @@ impl Assembler for LabelAssembler {
     }
 
     fn lookup(&self, name: &str) -> Result<u32, Self::Err> {
-        Ok(*self.labels.get(name).unwrap_or(&0xffffffff))
+        Ok(self
+            .labels
+            .get(name)
+            .copied()
+            .unwrap_or(self.current_address()))
     }
 
     fn emit(&mut self, _bits: impl Bits) -> Result<(), Self::Err> {
-        self.offset += 4;
+        self.address = self.address.wrapping_add(4);
+        Ok(())
+    }
+
+    fn org(&mut self, address: u32) -> Result<(), Self::Err> {
+        ensure!(
+            address.is_multiple_of(4),
+            ".org {:#x} is not word aligned",
+            address
+        );
+        self.address = address;
         Ok(())
     }
 }
 
 pub struct OutputAssembler {
-    base_addr: u32,
-    labels: BTreeMap<String, u32>,
-    output: Vec<u8>,
+    pub(crate) labels: BTreeMap<String, u32>,
+    pub(crate) regions: Vec<Region>,
+    /// The words emitted since this was last cleared.
+    pub(crate) emitted: Vec<u32>,
 }
 
 impl OutputAssembler {
     pub fn new(base_addr: u32, labels: BTreeMap<String, u32>) -> Self {
         Self {
-            base_addr,
             labels,
-            output: Default::default(),
+            regions: vec![Region {
+                address: base_addr,
+                code: vec![],
+            }],
+            emitted: vec![],
         }
     }
 }
@@ -95,7 +201,7 @@ impl Assembler for OutputAssembler {
     type Err = anyhow::Error;
 
     fn current_address(&self) -> u32 {
-        self.base_addr + self.output.len() as u32
+        self.regions.last().unwrap().end() as u32
     }
 
     fn label(&mut self, name: &str, address: u32) -> Result<(), Self::Err> {
@@ -113,8 +219,71 @@ impl Assembler for OutputAssembler {
     }
 
     fn emit(&mut self, bits: impl Bits) -> Result<(), Self::Err> {
-        self.output.extend_from_slice(&bits.bits().to_be_bytes());
+        let address = self.current_address();
+        ensure!(
+            self.regions.last().unwrap().end() < 1 << 32,
+            "Code runs past the end of the address space"
+        );
+        if let Some(region) = self
+            .regions
+            .iter()
+            .find(|region| region.address <= address && (address as u64) < region.end())
+        {
+            bail!(
+                "Code at {:#x} overlaps the region at {:#x}",
+                address,
+                region.address
+            );
+        }
+        let region = self.regions.last_mut().unwrap();
+        region.code.extend_from_slice(&bits.bits().to_be_bytes());
+        self.emitted.push(bits.bits());
+
+        Ok(())
+    }
 
+    fn org(&mut self, address: u32) -> Result<(), Self::Err> {
+        ensure!(
+            address.is_multiple_of(4),
+            ".org {:#x} is not word aligned",
+            address
+        );
+        if self.regions.last().unwrap().code.is_empty() {
+            self.regions.pop();
+        }
+        self.regions.push(Region {
+            address,
+            code: vec![],
+        });
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn org_regions() {
+        let source =
+            "addi r1, r0, 1\n.org 0x2000\nlbl far\naddi r2, r0, 2\n.org 0x1008\ndword 0x5\n";
+        let (regions, labels) =
+            assemble_regions_with(&Extensions::default(), 0x1000, source).unwrap();
+        assert_eq!(
+            regions
+                .iter()
+                .map(|region| (region.address, region.code.len()))
+                .collect::<Vec<_>>(),
+            [(0x1000, 4), (0x2000, 4), (0x1008, 4)]
+        );
+        assert_eq!(labels["far"], 0x2000);
+
+        let (code, _) = crate::assemble(0x1000, source).unwrap();
+        assert_eq!(code.len(), 0x1004);
+        assert_eq!(&code[8..12], &[0, 0, 0, 5]);
+
+        assert!(crate::assemble(0x1000, "dword 0x1\ndword 0x2\n.org 0x1004\ndword 0x3\n").is_err());
+        assert!(crate::assemble(0x1000, ".org 0x800\ndword 0x1\n").is_err());
+        assert!(crate::assemble(0x1000, ".org 0x1002\n").is_err());
+    }
+}
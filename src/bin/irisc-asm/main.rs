@@ -1,55 +1,873 @@
-use std::{collections::BTreeMap, path::PathBuf};
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    io::Write,
+    net::TcpListener,
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::{Arc, Mutex},
+};
 
-use anyhow::Result;
-use clap::Parser;
-use serde::{Deserialize, Serialize};
-use serde_with::serde_as;
+use anyhow::{bail, ensure, Context, Result};
+use clap::{Args, Parser, Subcommand};
 
-use irisc_asm::assemble_template;
-use irisc_asm::utils::{cartesian_product, parse_parameter};
-
-#[serde_as]
-#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
-struct Shellcode {
-    #[serde_as(as = "serde_with::hex::Hex")]
-    code: Vec<u8>,
-    parameters: BTreeMap<String, u64>,
-    labels: BTreeMap<String, u32>,
-}
+use irisc_asm::assembler::{flatten, Region};
+use irisc_asm::debugger::Debugger;
+use irisc_asm::emu::csr::CsrSpace;
+use irisc_asm::emu::delay::DelaySlots;
+use irisc_asm::emu::device::{Logger, Ram, Rom};
+use irisc_asm::emu::trace::TraceFilter;
+use irisc_asm::emu::Emulator;
+use irisc_asm::extensions::Extensions;
+use irisc_asm::fields::Reg;
+use irisc_asm::infer::{self, AluLayout, BranchLayout};
+use irisc_asm::listing::listing_with;
+use irisc_asm::object::{self, assemble_object_with, Object};
+use irisc_asm::output::{elf, Format, Options, Shellcode};
+use irisc_asm::patch::Firmware;
+use irisc_asm::pic::position_dependent;
+use irisc_asm::source_map::SourceMap;
+use irisc_asm::utils::{
+    cartesian_product, format_parameters, parse_address, parse_address_bytes, parse_address_range,
+    parse_byte, parse_parameter, parse_path_address, parse_register_value,
+};
+use irisc_asm::verify::{read_jsonl, Observation, Setup};
+use irisc_asm::{assemble_regions_with, forbid, lint, render_template};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
-struct Args {
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    assemble: AssembleArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Disassemble raw, Intel HEX, S-record or ELF files
+    Disasm(DisasmArgs),
+    /// Link objects assembled with --object
+    Link(LinkArgs),
+    /// Assemble code into declared regions of a firmware image
+    Patch(PatchArgs),
+    /// Emulate raw, Intel HEX, S-record or ELF files, optionally tracing each instruction
+    Run(RunArgs),
+    /// Debug assembly source in the emulator, reading commands from stdin
+    Debug(DebugArgs),
+    /// Serve assembly source in the emulator to a GDB client over TCP
+    Gdb(GdbArgs),
+    /// Compare emulated runs of json output with hardware results
+    Verify(VerifyArgs),
+    /// Find the operation of each alu.r funct from hardware results
+    InferAlu(InferAluArgs),
+    /// Find the condition of each b.t/b.f cmpop from hardware results
+    InferBranch(InferBranchArgs),
+}
+
+#[derive(Args, Debug)]
+struct AssembleArgs {
+    #[arg(required = true)]
+    input: Option<PathBuf>,
+
+    /// Where to write the assembled code, `-` for stdout
+    #[arg(required = true)]
+    output: Option<PathBuf>,
+
+    #[arg(short, long, default_value_t = 0, value_parser = parse_address, conflicts_with = "object")]
+    base_addr: u32,
+
+    #[arg(short, long, value_parser = parse_parameter)]
+    param: Vec<(String, Vec<u64>)>,
+
+    /// TOML file with additional instruction definitions
+    #[arg(long)]
+    isa_ext: Vec<PathBuf>,
+
+    /// Write a relocatable object for `link` instead of code
+    #[arg(long)]
+    object: bool,
+
+    /// Also write the address and words of every source line to this file
+    #[arg(long)]
+    listing: Option<PathBuf>,
+
+    /// Also write the source line of every address to this JSON file
+    #[arg(long)]
+    source_map: Option<PathBuf>,
+
+    /// Include the source map in json output
+    #[arg(long)]
+    embed_source_map: bool,
+
+    /// Comma separated bytes the code must not contain
+    #[arg(long, value_delimiter = ',', value_parser = parse_byte, conflicts_with = "object")]
+    forbid_bytes: Vec<u8>,
+
+    /// Fail if the code of any parameter combination is larger than this many bytes
+    #[arg(long)]
+    max_size: Option<usize>,
+
+    /// Fail if the code depends on the base address, so it can't run anywhere
+    #[arg(long, conflicts_with = "object")]
+    pic: bool,
+
+    /// Warn about questionable delay slots, given `kind=n,...` with kinds retd, jump, call,
+    /// branch and all
+    #[arg(long)]
+    delay_slots: Option<DelaySlots>,
+
+    #[command(flatten)]
+    format: FormatArgs,
+}
+
+#[derive(Args, Debug)]
+struct FormatArgs {
+    /// Output format: raw, hex, json, c, rust, ihex, srec, elf32 or elf64
+    #[arg(short, long, default_value_t = Format::Json)]
+    format: Format,
+
+    /// Name of the array in c and rust output
+    #[arg(long, default_value = "shellcode")]
+    name: String,
+
+    /// Machine number of elf32 and elf64 output
+    #[arg(long, default_value_t = elf::DEFAULT_MACHINE)]
+    elf_machine: u16,
+}
+
+impl FormatArgs {
+    fn write(self, base_addr: u32, shellcodes: &[Shellcode]) -> Result<Vec<u8>> {
+        let options = Options {
+            base_addr,
+            name: self.name,
+            elf_machine: self.elf_machine,
+        };
+        let mut output = vec![];
+        self.format.write(&mut output, &options, shellcodes)?;
+        Ok(output)
+    }
+}
+
+#[derive(Args, Debug)]
+struct LinkArgs {
+    #[arg(required = true)]
+    objects: Vec<PathBuf>,
+
+    /// Where to write the linked code, `-` for stdout
+    #[arg(short, long)]
+    output: PathBuf,
+
+    #[arg(short, long, default_value_t = 0, value_parser = parse_address)]
+    base_addr: u32,
+
+    #[command(flatten)]
+    format: FormatArgs,
+}
+
+#[derive(Args, Debug)]
+struct DisasmArgs {
     input: PathBuf,
 
+    /// Address raw input is loaded at
+    #[arg(short, long, default_value_t = 0, value_parser = parse_address)]
+    base_addr: u32,
+
+    /// Input format: raw, ihex, srec, elf32 or elf64
+    #[arg(short, long, default_value_t = Format::Raw)]
+    format: Format,
+
+    /// TOML file with additional instruction definitions
+    #[arg(long)]
+    isa_ext: Vec<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct RunArgs {
+    input: PathBuf,
+
+    /// Address raw input is loaded at
+    #[arg(short, long, default_value_t = 0, value_parser = parse_address)]
+    base_addr: u32,
+
+    /// Input format: raw, ihex, srec, elf32 or elf64
+    #[arg(short, long, default_value_t = Format::Raw)]
+    format: Format,
+
+    #[command(flatten)]
+    machine: MachineArgs,
+
+    /// Stop after this many instructions
+    #[arg(long, default_value_t = 100_000)]
+    max_steps: u64,
+
+    /// Print every CSR access
+    #[arg(long)]
+    csr_log: bool,
+
+    /// Trace each instruction as text or as one JSON object per line
+    #[arg(short, long, value_parser = ["text", "json"])]
+    trace: Option<String>,
+
+    /// Only trace instructions in `start..end`, may be given several times
+    #[arg(long, value_parser = parse_address_range)]
+    trace_range: Vec<(u32, u64)>,
+
+    /// Stop tracing after this many instructions
+    #[arg(long)]
+    trace_limit: Option<usize>,
+
+    /// Where to write the trace, `-` for stdout
+    #[arg(short, long, default_value = "-")]
     output: PathBuf,
+}
 
-    #[arg(short, long, default_value_t = 0)]
+#[derive(Args, Debug)]
+struct DebugArgs {
+    /// Assembly source, its labels can be used as locations
+    input: PathBuf,
+
+    #[arg(short, long, default_value_t = 0, value_parser = parse_address)]
     base_addr: u32,
 
+    /// Template parameter `key=value`, with a single value
     #[arg(short, long, value_parser = parse_parameter)]
     param: Vec<(String, Vec<u64>)>,
+
+    #[command(flatten)]
+    machine: MachineArgs,
+
+    /// Stop `continue` after this many instructions
+    #[arg(long, default_value_t = 100_000)]
+    max_steps: u64,
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
+#[derive(Args, Debug)]
+struct GdbArgs {
+    #[command(flatten)]
+    debug: DebugArgs,
 
-    let template = std::fs::read_to_string(&args.input)?;
+    /// Address to listen on
+    #[arg(long, default_value = "127.0.0.1:1234")]
+    listen: String,
+}
+
+/// How the emulated machine is set up.
+#[derive(Args, Debug)]
+struct MachineArgs {
+    /// Initial register value `rN=value`
+    #[arg(short, long = "reg", value_parser = parse_register_value)]
+    registers: Vec<(Reg, u64)>,
+
+    /// TOML file with rules for unknown instructions
+    #[arg(short, long)]
+    semantics: Vec<PathBuf>,
+
+    /// TOML file with the CSRs the code may access
+    #[arg(long)]
+    csrs: Vec<PathBuf>,
+
+    /// Delay slots after control transfers, `kind=n,...` with kinds retd, jump, call, branch
+    /// and all
+    #[arg(long, default_value_t)]
+    delay_slots: DelaySlots,
+
+    /// Map RAM at `start..end`
+    #[arg(long, value_parser = parse_address_range)]
+    ram: Vec<(u32, u64)>,
+
+    /// Map a firmware image read-only at `path@address`
+    #[arg(long, value_parser = parse_path_address)]
+    rom: Vec<(PathBuf, u32)>,
+
+    /// Map RAM at `start..end` and print every access to it
+    #[arg(long, value_parser = parse_address_range)]
+    log_device: Vec<(u32, u64)>,
+
+    /// TOML file with additional instruction definitions
+    #[arg(long)]
+    isa_ext: Vec<PathBuf>,
+}
+
+type LogDevices = Vec<(u32, Arc<Mutex<Logger<Ram>>>)>;
+
+impl MachineArgs {
+    /// An emulator with `regions` loaded, and the logging devices by start address.
+    fn emulator(&self, regions: &[Region]) -> Result<(Emulator, LogDevices)> {
+        let mut emulator = Emulator::new(load_extensions(&self.isa_ext)?);
+        for path in self.semantics.iter() {
+            emulator.semantics.load(path)?;
+        }
+        emulator.csrs = load_csrs(&self.csrs)?;
+        emulator.delay_slots = self.delay_slots;
+        for (start, end) in self.ram.iter() {
+            emulator.devices.map(*start as u64, *end, Ram::default())?;
+        }
+        for (path, address) in self.rom.iter() {
+            let image = std::fs::read(path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            let end = *address as u64 + image.len() as u64;
+            emulator.devices.map(*address as u64, end, Rom(image))?;
+        }
+        let mut loggers = vec![];
+        for (start, end) in self.log_device.iter() {
+            let logger = Logger::new(Ram::default());
+            loggers.push((*start, emulator.devices.map(*start as u64, *end, logger)?));
+        }
+        emulator.load(regions);
+        for (reg, value) in self.registers.iter() {
+            emulator.set_reg(*reg, *value);
+        }
+        Ok((emulator, loggers))
+    }
+}
+
+#[derive(Args, Debug)]
+struct VerifyArgs {
+    /// Json output of the assembler, one shellcode per line
+    shellcodes: PathBuf,
+
+    /// One `{"parameters": {...}, "output": "<hex>"}` per line with the hardware's output buffer
+    results: PathBuf,
+
+    /// Address the code was assembled at
+    #[arg(short, long, default_value_t = 0, value_parser = parse_address)]
+    base_addr: u32,
+
+    /// Output buffer range `start..end`
+    #[arg(long, value_parser = parse_address_range)]
+    buffer: (u32, u64),
+
+    /// Stop each run after this many instructions
+    #[arg(long, default_value_t = 100_000)]
+    max_steps: u64,
+
+    #[command(flatten)]
+    machine: MachineArgs,
+}
+
+#[derive(Args, Debug)]
+struct InferAluArgs {
+    /// One `{"parameters": {...}, "output": "<hex>"}` per line with the hardware's output buffer
+    results: PathBuf,
+
+    /// Parameter holding the funct
+    #[arg(long, default_value = "funct")]
+    funct: String,
+
+    /// Parameter holding the value of rs
+    #[arg(long, default_value = "r5")]
+    lhs: String,
+
+    /// Parameter holding the value of rt
+    #[arg(long, default_value = "r6")]
+    rhs: String,
+
+    /// Offset of the 64-bit result in the output buffer
+    #[arg(long, default_value = "0x18", value_parser = parse_address)]
+    result_offset: u32,
+}
+
+#[derive(Args, Debug)]
+struct InferBranchArgs {
+    /// One `{"parameters": {...}, "output": "<hex>"}` per line with the hardware's output buffer
+    results: PathBuf,
+
+    /// Parameter holding the cmpop
+    #[arg(long, default_value = "jmpop")]
+    cmpop: String,
+
+    /// Parameter holding the first operand of the flag-setting instruction
+    #[arg(long, default_value = "r5")]
+    lhs: String,
 
-    for parameters in cartesian_product(args.param)
+    /// Parameter holding the second operand of the flag-setting instruction
+    #[arg(long, default_value = "r6")]
+    rhs: String,
+
+    /// Offset of the 64-bit result of the flag-setting instruction in the output buffer
+    #[arg(long, default_value = "0x18", value_parser = parse_address)]
+    result_offset: u32,
+
+    /// Offset of the 32-bit word that is non-zero if the branch was taken
+    #[arg(long, default_value = "0x10", value_parser = parse_address)]
+    taken_offset: u32,
+
+    /// The branch tested, b.t or b.f
+    #[arg(long, default_value = "b.f", value_parser = ["b.t", "b.f"])]
+    branch: String,
+}
+
+#[derive(Args, Debug)]
+struct PatchArgs {
+    firmware: PathBuf,
+
+    /// Assembly placing its code with .org and hooking instructions with .hook
+    input: PathBuf,
+
+    /// Where to write the patched image, `-` for stdout
+    #[arg(short, long)]
+    output: PathBuf,
+
+    /// Address the firmware image is loaded at
+    #[arg(short, long, default_value_t = 0, value_parser = parse_address)]
+    load_addr: u32,
+
+    /// Range `start..end` the code may be written to
+    #[arg(short, long = "region", required = true, value_parser = parse_address_range)]
+    regions: Vec<(u32, u64)>,
+
+    /// Bytes `address=hex` the image must hold before patching
+    #[arg(short, long, value_parser = parse_address_bytes)]
+    expect: Vec<(u32, Vec<u8>)>,
+
+    #[arg(short, long, value_parser = parse_parameter)]
+    param: Vec<(String, Vec<u64>)>,
+
+    /// TOML file with additional instruction definitions
+    #[arg(long)]
+    isa_ext: Vec<PathBuf>,
+}
+
+fn load_extensions(paths: &[PathBuf]) -> Result<Extensions> {
+    let mut extensions = Extensions::default();
+    for path in paths {
+        extensions.load(path)?;
+    }
+    Ok(extensions)
+}
+
+fn assemble(args: AssembleArgs) -> Result<()> {
+    let (Some(input), Some(output)) = (&args.input, &args.output) else {
+        unreachable!("clap requires both without a subcommand")
+    };
+    let template = std::fs::read_to_string(input)?;
+    let extensions = load_extensions(&args.isa_ext)?;
+
+    let combinations = cartesian_product(args.param)
         .into_iter()
         .map(BTreeMap::from_iter)
-    {
-        let (code, labels) = assemble_template(args.base_addr, &template, &parameters)?;
-        println!(
-            "{}",
-            serde_json::to_string(&Shellcode {
-                parameters,
-                code,
-                labels,
-            })?
+        .collect::<Vec<BTreeMap<_, _>>>();
+    if args.object && combinations.len() != 1 {
+        bail!("An object needs exactly one parameter combination");
+    }
+    let base_addr = args.base_addr;
+    let file = input.display().to_string();
+    let with_source_map = args.source_map.is_some() || args.embed_source_map;
+    let with_listing = args.listing.is_some()
+        || with_source_map
+        || !args.forbid_bytes.is_empty()
+        || args.pic
+        || args.delay_slots.is_some();
+
+    let mut listings = vec![];
+    let mut source_maps = vec![];
+    let mut violations = vec![];
+    let mut position_dependent_lines = vec![];
+    let mut shellcodes = vec![];
+    let mut object = None;
+    for parameters in combinations.iter() {
+        let source = render_template(&template, parameters)?;
+        let heading = format_parameters(parameters);
+        let labelled = |message: String| match combinations.len() {
+            1 => message,
+            _ => format!("{}: {}", heading, message),
+        };
+        let listing = match with_listing {
+            true => Some(listing_with(&extensions, base_addr, &source)?),
+            false => None,
+        };
+        if let Some(listing) = &listing {
+            if args.listing.is_some() {
+                listings.push(match combinations.len() {
+                    1 => listing.to_string(),
+                    _ => format!("# {}\n{}", heading, listing),
+                });
+            }
+            if with_source_map {
+                source_maps.push(SourceMap::new(
+                    &file,
+                    &template,
+                    parameters.clone(),
+                    listing,
+                ));
+            }
+            if let Some(slots) = &args.delay_slots {
+                for warning in lint::delay_slots(&extensions, listing, slots) {
+                    eprintln!("warning: {}", labelled(warning.to_string()));
+                }
+            }
+            if args.pic {
+                for line in position_dependent(&extensions, base_addr, &source, listing)? {
+                    let problem = format!("line {}: `{}`", line.line, line.text.trim());
+                    position_dependent_lines.push(labelled(problem));
+                }
+            }
+        }
+
+        if args.object {
+            object = Some(assemble_object_with(&extensions, &source)?);
+            continue;
+        }
+        let (regions, labels) = match listing {
+            Some(listing) => {
+                let code = flatten(base_addr, &listing.regions)?;
+                for violation in
+                    forbid::check(&extensions, &listing, base_addr, &code, &args.forbid_bytes)
+                {
+                    violations.push(labelled(violation.to_string()));
+                }
+                (listing.regions, listing.labels)
+            }
+            None => assemble_regions_with(&extensions, base_addr, &source)?,
+        };
+        let mut shellcode = Shellcode::new(base_addr, parameters.clone(), regions, labels)?;
+        if args.embed_source_map {
+            shellcode.source_map = source_maps.last().map(|map| map.locations.clone());
+        }
+        shellcodes.push(shellcode);
+    }
+    if let Some(path) = &args.listing {
+        write_output(path, listings.join("\n").into_bytes())?;
+    }
+    if let Some(path) = &args.source_map {
+        write_output(path, serde_json::to_vec(&source_maps)?)?;
+    }
+    if !violations.is_empty() {
+        bail!("Forbidden bytes in the code:\n{}", violations.join("\n"));
+    }
+    if !position_dependent_lines.is_empty() {
+        bail!(
+            "The code depends on the base address:\n{}",
+            position_dependent_lines.join("\n")
         );
     }
+    if let Some(max_size) = args.max_size {
+        let sizes = match &object {
+            Some(object) => vec![(object.code.len(), String::new())],
+            None => shellcodes
+                .iter()
+                .map(|shellcode| {
+                    let heading = match combinations.len() {
+                        1 => String::new(),
+                        _ => format!(" for {}", format_parameters(&shellcode.parameters)),
+                    };
+                    (shellcode.code.len(), heading)
+                })
+                .collect(),
+        };
+        for (size, heading) in sizes {
+            ensure!(
+                size <= max_size,
+                "The code is {} bytes, more than --max-size {}{}",
+                size,
+                max_size,
+                heading
+            );
+        }
+    }
+
+    if let Some(object) = object {
+        return write_output(output, serde_json::to_vec(&object)?);
+    }
+    write_output(output, args.format.write(base_addr, &shellcodes)?)
+}
+
+fn link(args: LinkArgs) -> Result<()> {
+    let objects = args
+        .objects
+        .iter()
+        .map(|path| Ok((path.display().to_string(), Object::load(path)?)))
+        .collect::<Result<Vec<_>>>()?;
+    let (code, labels) = object::link(args.base_addr, &objects)?;
+    let regions = vec![Region {
+        address: args.base_addr,
+        code,
+    }];
+    let shellcode = Shellcode::new(args.base_addr, BTreeMap::new(), regions, labels)?;
+
+    let output = args.format.write(args.base_addr, &[shellcode])?;
+    write_output(&args.output, output)
+}
+
+fn patch(args: PatchArgs) -> Result<()> {
+    let image = std::fs::read(&args.firmware)
+        .with_context(|| format!("Failed to read {}", args.firmware.display()))?;
+    let template = std::fs::read_to_string(&args.input)?;
+    let extensions = load_extensions(&args.isa_ext)?;
+
+    let combinations = cartesian_product(args.param);
+    let [parameters] = combinations.as_slice() else {
+        bail!("A patch needs exactly one parameter combination");
+    };
+    let source = render_template(&template, &BTreeMap::from_iter(parameters.clone()))?;
+
+    let mut firmware = Firmware {
+        load_addr: args.load_addr,
+        image,
+    };
+    for (address, code) in args.expect {
+        firmware.verify(&Region { address, code })?;
+    }
+    let (source, hooks) = firmware.expand_hooks(&source, &extensions)?;
+    let (code, _) = assemble_regions_with(&extensions, args.load_addr, &source)?;
+    firmware.patch(&code, &[args.regions, hooks].concat())?;
+    write_output(&args.output, firmware.image)
+}
+
+fn run(args: RunArgs) -> Result<()> {
+    let data = std::fs::read(&args.input)
+        .with_context(|| format!("Failed to read {}", args.input.display()))?;
+    let regions = args.format.read(&data, args.base_addr)?;
+    let (mut emulator, loggers) = args.machine.emulator(&regions)?;
+
+    let stop = match args.trace.as_deref() {
+        None => emulator.run(args.max_steps),
+        Some(format) => {
+            let filter = TraceFilter {
+                ranges: args.trace_range,
+                limit: args.trace_limit,
+            };
+            let mut trace = String::new();
+            let stop = emulator.run_traced(args.max_steps, &filter, |entry| {
+                let line = match format {
+                    "json" => serde_json::to_string(entry).expect("entries serialize"),
+                    _ => entry.to_string(),
+                };
+                trace.push_str(&line);
+                trace.push('\n');
+            });
+            write_output(&args.output, trace.into_bytes())?;
+            stop
+        }
+    };
+
+    if args.csr_log {
+        for access in emulator.csrs.log.iter() {
+            eprintln!("{}", access);
+        }
+    }
+    for (start, logger) in loggers {
+        for mut access in logger.lock().unwrap().log.iter().copied() {
+            access.address += start as u64;
+            eprintln!("{}", access);
+        }
+    }
+    eprintln!("{} after {} instructions", stop, emulator.steps);
+    for (index, value) in emulator.registers.iter().enumerate() {
+        if *value != 0 {
+            eprintln!("r{:<2} = {:#018x}", index, value);
+        }
+    }
+    Ok(())
+}
+
+impl DebugArgs {
+    fn debugger(self) -> Result<Debugger> {
+        let template = std::fs::read_to_string(&self.input)
+            .with_context(|| format!("Failed to read {}", self.input.display()))?;
+        let mut parameters = BTreeMap::new();
+        for (key, values) in self.param {
+            let [value] = values[..] else {
+                bail!("The debugger needs a single value for {}", key);
+            };
+            parameters.insert(key, value);
+        }
+        let source = render_template(&template, &parameters)?;
+        let extensions = load_extensions(&self.machine.isa_ext)?;
+        let (regions, labels) = assemble_regions_with(&extensions, self.base_addr, &source)?;
+        let (emulator, _) = self.machine.emulator(&regions)?;
+        let mut debugger = Debugger::new(emulator, &regions, labels);
+        debugger.max_steps = self.max_steps;
+        Ok(debugger)
+    }
+}
+
+fn debug(args: DebugArgs) -> Result<()> {
+    let mut debugger = args.debugger()?;
+
+    println!("{}", debugger.execute("disas")?);
+    let mut last = String::new();
+    for line in std::io::stdin().lines() {
+        let line = line?;
+        // An empty line repeats the last command, like stepping again.
+        let command = match line.trim() {
+            "" => last.clone(),
+            command => command.to_string(),
+        };
+        if matches!(command.as_str(), "quit" | "q") {
+            break;
+        }
+        match debugger.execute(&command) {
+            Ok(output) if output.is_empty() => {}
+            Ok(output) => println!("{}", output),
+            Err(error) => println!("Error: {:#}", error),
+        }
+        last = command;
+    }
+    Ok(())
+}
+
+fn gdb(args: GdbArgs) -> Result<()> {
+    let mut debugger = args.debug.debugger()?;
+    let listener = TcpListener::bind(&args.listen)
+        .with_context(|| format!("Failed to listen on {}", args.listen))?;
+    eprintln!("Waiting for GDB on {}", listener.local_addr()?);
+    irisc_asm::gdb::serve(&mut debugger, &listener)
+}
+
+fn verify(args: VerifyArgs) -> Result<()> {
+    let shellcodes = std::fs::read_to_string(&args.shellcodes)
+        .with_context(|| format!("Failed to read {}", args.shellcodes.display()))?;
+    let shellcodes = read_jsonl::<Shellcode>(&shellcodes)
+        .with_context(|| format!("Bad shellcodes {}", args.shellcodes.display()))?;
+    let observations = read_observations(&args.results)?;
+    let runs = Rc::new(RefCell::new(vec![]));
+    let setup = Setup {
+        base_addr: args.base_addr,
+        buffer: args.buffer,
+        max_steps: args.max_steps,
+        emulator: Box::new({
+            let runs = runs.clone();
+            move |regions| {
+                let (emulator, loggers) = args.machine.emulator(regions)?;
+                runs.borrow_mut().push(loggers);
+                Ok(emulator)
+            }
+        }),
+    };
+
+    let report = irisc_asm::verify::verify(&setup, &shellcodes, &observations)?;
+    for (index, loggers) in runs.borrow().iter().enumerate() {
+        for (start, logger) in loggers {
+            for mut access in logger.lock().unwrap().log.iter().copied() {
+                access.address += *start as u64;
+                eprintln!("run {}: {}", index + 1, access);
+            }
+        }
+    }
+    for mismatch in report.mismatches.iter() {
+        println!("{}", mismatch);
+    }
+    for parameters in report.missing.iter() {
+        println!("{}: no hardware result", format_parameters(parameters));
+    }
+    println!(
+        "{} matched, {} differ, {} without results",
+        report.matched,
+        report.mismatches.len(),
+        report.missing.len()
+    );
+    ensure!(
+        report.mismatches.is_empty(),
+        "The emulator differs from the hardware"
+    );
+    Ok(())
+}
+
+fn load_csrs(paths: &[PathBuf]) -> Result<CsrSpace> {
+    let mut csrs = CsrSpace::default();
+    for path in paths {
+        csrs.load(path)?;
+    }
+    Ok(csrs)
+}
+
+fn read_observations(path: &Path) -> Result<Vec<Observation>> {
+    let results = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    read_jsonl(&results).with_context(|| format!("Bad results {}", path.display()))
+}
+
+fn infer_alu(args: InferAluArgs) -> Result<()> {
+    let observations = read_observations(&args.results)?;
+    let layout = AluLayout {
+        funct: args.funct,
+        lhs: args.lhs,
+        rhs: args.rhs,
+        result_offset: args.result_offset as usize,
+    };
+    for (funct, samples) in layout.samples(&observations)? {
+        println!("funct {:#05x}: {}", funct, infer::infer_alu(&samples));
+    }
+    Ok(())
+}
+
+fn infer_branch(args: InferBranchArgs) -> Result<()> {
+    let observations = read_observations(&args.results)?;
+    let layout = BranchLayout {
+        cmpop: args.cmpop,
+        lhs: args.lhs,
+        rhs: args.rhs,
+        result_offset: args.result_offset as usize,
+        taken_offset: args.taken_offset as usize,
+        negated: args.branch == "b.f",
+    };
+    for (cmpop, samples) in layout.samples(&observations)? {
+        let inference = infer::infer_branch(&samples);
+        println!("cmpop {:#04x}: {}", cmpop, inference);
+        for index in inference.mismatches.iter().take(5) {
+            let sample = &samples[*index];
+            let taken = if sample.holds != layout.negated {
+                "taken"
+            } else {
+                "not taken"
+            };
+            println!(
+                "    {} for {}",
+                taken,
+                format_parameters(&sample.parameters)
+            );
+        }
+        if inference.mismatches.len() > 5 {
+            println!("    and {} more", inference.mismatches.len() - 5);
+        }
+    }
+    Ok(())
+}
 
+fn write_output(path: &Path, output: Vec<u8>) -> Result<()> {
+    if path.as_os_str() == "-" {
+        std::io::stdout().write_all(&output)?;
+    } else {
+        std::fs::write(path, output)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+    }
     Ok(())
 }
+
+fn disasm(args: DisasmArgs) -> Result<()> {
+    let data = std::fs::read(&args.input)
+        .with_context(|| format!("Failed to read {}", args.input.display()))?;
+    let extensions = load_extensions(&args.isa_ext)?;
+
+    for (index, region) in args.format.read(&data, args.base_addr)?.iter().enumerate() {
+        if index > 0 {
+            println!();
+        }
+        for (address, word, instruction) in region.disassemble(&extensions) {
+            println!("{:08x}: {:08x}  {}", address, word, instruction);
+        }
+    }
+
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+
+    match cli.command {
+        Some(Command::Disasm(args)) => disasm(args),
+        Some(Command::Link(args)) => link(args),
+        Some(Command::Patch(args)) => patch(args),
+        Some(Command::Run(args)) => run(args),
+        Some(Command::Debug(args)) => debug(args),
+        Some(Command::Gdb(args)) => gdb(args),
+        Some(Command::Verify(args)) => verify(args),
+        Some(Command::InferAlu(args)) => infer_alu(args),
+        Some(Command::InferBranch(args)) => infer_branch(args),
+        None => assemble(cli.assemble),
+    }
+}
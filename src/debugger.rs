@@ -0,0 +1,436 @@
+//! An interactive debugger for the emulator.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::{bail, ensure, Context, Result};
+
+use crate::{
+    assembler::Region,
+    emu::{Emulator, Stop, RETURN_ADDRESS},
+    fields::Reg,
+    instructions::Instruction,
+    utils::parse_number,
+};
+
+const HELP: &str = "\
+break|b LOC          stop before executing LOC
+delete|d LOC         remove the breakpoint at LOC
+watch|w LOC [LEN]    stop after the code writes to LEN bytes at LOC, 8 by default
+unwatch LOC          remove the watchpoint at LOC
+info|i               list breakpoints and watchpoints
+step|s [N]           execute N instructions, 1 by default
+continue|c           execute until a breakpoint, a watchpoint or the end
+regs|r               show the registers
+set REG VALUE        change a register, or pc
+x LOC [LEN]          show LEN bytes of memory at LOC, 64 by default and 4096 at most
+disas|l [LOC] [N]    disassemble N instructions at LOC, around the pc by default
+labels               list the labels
+LOC is a label or an address.";
+
+/// The most bytes `x` shows at once.
+const MAX_DUMP: u64 = 0x1000;
+
+/// Why execution paused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Pause {
+    Stepped,
+    Breakpoint(u32),
+    /// The instruction at `address` wrote to the watched range at `watched`.
+    Watchpoint {
+        address: u32,
+        watched: u64,
+    },
+    Stopped(Stop),
+}
+
+/// An emulator with breakpoints, watchpoints and labels.
+#[derive(Debug, Clone)]
+pub struct Debugger {
+    pub emulator: Emulator,
+    pub labels: BTreeMap<String, u32>,
+    /// The address ranges of the code, `start..end`.
+    pub code: Vec<(u32, u64)>,
+    pub breakpoints: BTreeSet<u32>,
+    /// Watched ranges, `start` to `len`.
+    pub watchpoints: BTreeMap<u64, u64>,
+    /// How many instructions `continue` executes at most.
+    pub max_steps: u64,
+}
+
+impl Debugger {
+    pub fn new(emulator: Emulator, regions: &[Region], labels: BTreeMap<String, u32>) -> Self {
+        let code = regions
+            .iter()
+            .map(|region| {
+                let end = region.address as u64 + region.code.len() as u64;
+                (region.address, end)
+            })
+            .collect();
+        Self {
+            emulator,
+            labels,
+            code,
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeMap::new(),
+            max_steps: 100_000,
+        }
+    }
+
+    /// Parses a label or an address.
+    pub fn location(&self, s: &str) -> Result<u32> {
+        if let Some(address) = self.labels.get(s) {
+            return Ok(*address);
+        }
+        let address = parse_number(s).with_context(|| format!("No label or address {}", s))?;
+        u32::try_from(address).with_context(|| format!("Address out of range: {}", s))
+    }
+
+    /// The code range holding `address`.
+    fn code_range(&self, address: u32) -> Option<(u32, u64)> {
+        self.code
+            .iter()
+            .copied()
+            .find(|(start, end)| *start <= address && (address as u64) < *end)
+    }
+
+    /// `address` relative to the closest label at or before it in the same
+    /// code range, e.g. `loop+0x4`.
+    pub fn symbolize(&self, address: u32) -> Option<String> {
+        let (code_start, _) = self.code_range(address)?;
+        let (name, start) = self
+            .labels
+            .iter()
+            .filter(|(_, start)| (code_start..=address).contains(*start))
+            .max_by_key(|(name, start)| (**start, std::cmp::Reverse(*name)))?;
+        Some(match address - start {
+            0 => name.clone(),
+            offset => format!("{}+{:#x}", name, offset),
+        })
+    }
+
+    /// Executes one instruction, checking the watchpoints.
+    fn step(&mut self) -> Result<(), Pause> {
+        let address = self.emulator.pc;
+        if self.watchpoints.is_empty() {
+            return self.emulator.step().map_err(Pause::Stopped);
+        }
+        let (result, entry) = self.emulator.step_traced();
+        result.map_err(Pause::Stopped)?;
+        for access in entry.memory.iter().filter(|access| access.write) {
+            // An end past the address space is never below an address.
+            let below = |address: u64, start: u64, len: u64| {
+                start.checked_add(len).is_none_or(|end| address < end)
+            };
+            let hit = self.watchpoints.iter().find(|(start, len)| {
+                below(access.address, **start, **len)
+                    && below(**start, access.address, access.len as u64)
+            });
+            if let Some((start, _)) = hit {
+                return Err(Pause::Watchpoint {
+                    address,
+                    watched: *start,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Executes `count` instructions, stopping early at watchpoints.
+    pub fn step_n(&mut self, count: u64) -> Pause {
+        for _ in 0..count {
+            if let Err(pause) = self.step() {
+                return pause;
+            }
+        }
+        Pause::Stepped
+    }
+
+    /// Executes until a breakpoint or watchpoint is hit or the emulator stops.
+    pub fn resume(&mut self) -> Pause {
+        for _ in 0..self.max_steps {
+            if let Err(pause) = self.step() {
+                return pause;
+            }
+            if self.breakpoints.contains(&self.emulator.pc) {
+                return Pause::Breakpoint(self.emulator.pc);
+            }
+        }
+        Pause::Stopped(Stop::StepLimit)
+    }
+
+    /// Runs one command, returning what to print.
+    pub fn execute(&mut self, line: &str) -> Result<String> {
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        let Some((command, args)) = words.split_first() else {
+            return Ok(String::new());
+        };
+        let arg = |index: usize| args.get(index).copied();
+        let number = |index: usize, default: u64| arg(index).map_or(Ok(default), parse_number);
+        Ok(match *command {
+            "break" | "b" => {
+                let address = self.location(arg(0).context("break needs a location")?)?;
+                self.breakpoints.insert(address);
+                format!("Breakpoint at {}", self.describe(address))
+            }
+            "delete" | "d" => {
+                let address = self.location(arg(0).context("delete needs a location")?)?;
+                ensure!(
+                    self.breakpoints.remove(&address),
+                    "No breakpoint at {:#x}",
+                    address
+                );
+                format!("Deleted the breakpoint at {}", self.describe(address))
+            }
+            "watch" | "w" => {
+                let address = self.location(arg(0).context("watch needs a location")?)?;
+                let len = number(1, 8)?;
+                ensure!(len > 0, "Can't watch 0 bytes");
+                self.watchpoints.insert(address as u64, len);
+                format!("Watching {} bytes at {}", len, self.describe(address))
+            }
+            "unwatch" => {
+                let address = self.location(arg(0).context("unwatch needs a location")?)?;
+                ensure!(
+                    self.watchpoints.remove(&(address as u64)).is_some(),
+                    "No watchpoint at {:#x}",
+                    address
+                );
+                format!("Stopped watching {}", self.describe(address))
+            }
+            "info" | "i" => self.info(),
+            "step" | "s" => {
+                let pause = self.step_n(number(0, 1)?);
+                self.report(pause)
+            }
+            "continue" | "c" => {
+                let pause = self.resume();
+                self.report(pause)
+            }
+            "regs" | "r" => self.registers(),
+            "set" => {
+                let (Some(reg), Some(value)) = (arg(0), arg(1)) else {
+                    bail!("set needs a register and a value");
+                };
+                let value = parse_number(value)?;
+                match reg {
+                    "pc" => self.emulator.pc = u32::try_from(value).context("pc out of range")?,
+                    reg => {
+                        let reg: Reg = reg.parse()?;
+                        self.emulator.set_reg(reg, value);
+                    }
+                }
+                format!("{} = {:#x}", reg, value)
+            }
+            "x" => {
+                let address = self.location(arg(0).context("x needs a location")?)? as u64;
+                let len = number(1, 64)?;
+                ensure!(len <= MAX_DUMP, "x shows at most {:#x} bytes", MAX_DUMP);
+                let end = address.checked_add(len).context("Past the end of memory")?;
+                self.dump(address, end)
+            }
+            "disas" | "l" => match arg(0) {
+                Some(location) => {
+                    let address = self.location(location)?;
+                    self.disassemble(address, number(1, 10)?)
+                }
+                None => {
+                    let pc = self.emulator.pc;
+                    let code_start = self.code_range(pc).map_or(0, |(start, _)| start);
+                    self.disassemble(pc.saturating_sub(4 * 3).max(code_start), 10)
+                }
+            },
+            "labels" => self
+                .labels
+                .iter()
+                .map(|(name, address)| format!("{:08x} {}", address, name))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            "help" | "h" => HELP.to_string(),
+            command => bail!("Unknown command {}, try help", command),
+        })
+    }
+
+    fn describe(&self, address: u32) -> String {
+        match self.symbolize(address) {
+            Some(symbol) => format!("{:#x} <{}>", address, symbol),
+            None => format!("{:#x}", address),
+        }
+    }
+
+    fn report(&self, pause: Pause) -> String {
+        let reason = match pause {
+            Pause::Stepped => None,
+            Pause::Breakpoint(address) => Some(format!("Breakpoint at {}", self.describe(address))),
+            Pause::Watchpoint { address, watched } => Some(format!(
+                "Watchpoint at {:#x} written by {}",
+                watched,
+                self.describe(address)
+            )),
+            Pause::Stopped(stop) => Some(stop.to_string()),
+        };
+        let here = match self.emulator.pc {
+            RETURN_ADDRESS => None,
+            pc => Some(self.disassemble(pc, 1)),
+        };
+        [reason, here]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn info(&self) -> String {
+        let mut lines = vec![];
+        for address in self.breakpoints.iter() {
+            lines.push(format!("breakpoint {}", self.describe(*address)));
+        }
+        for (start, len) in self.watchpoints.iter() {
+            lines.push(format!("watchpoint {:#x}, {} bytes", start, len));
+        }
+        match lines.is_empty() {
+            true => "No breakpoints or watchpoints".to_string(),
+            false => lines.join("\n"),
+        }
+    }
+
+    fn registers(&self) -> String {
+        let emulator = &self.emulator;
+        let mut lines = emulator
+            .registers
+            .chunks(4)
+            .enumerate()
+            .map(|(row, values)| {
+                values
+                    .iter()
+                    .enumerate()
+                    .map(|(column, value)| format!("r{:<2} {:#018x}", row * 4 + column, value))
+                    .collect::<Vec<_>>()
+                    .join("  ")
+            })
+            .collect::<Vec<_>>();
+        lines.push(format!(
+            "pc  {}  link {:#x}  steps {}",
+            self.describe(emulator.pc),
+            emulator.link,
+            emulator.steps
+        ));
+        let flags = emulator.flags;
+        lines.push(format!(
+            "flags lhs {:#x}, rhs {:#x}, result {:#x}",
+            flags.lhs, flags.rhs, flags.result
+        ));
+        lines.join("\n")
+    }
+
+    fn dump(&self, address: u64, end: u64) -> String {
+        (address..end)
+            .step_by(16)
+            .map(|line| {
+                let bytes = (line..line.saturating_add(16).min(end))
+                    .map(|address| format!("{:02x}", self.emulator.peek(address, 1)))
+                    .collect::<Vec<_>>();
+                format!("{:08x}: {}", line, bytes.join(" "))
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn disassemble(&self, address: u32, count: u64) -> String {
+        (0..count)
+            .map(|index| address.wrapping_add(4 * index as u32))
+            .map(|address| {
+                let word = self.emulator.peek(address as u64, 4) as u32;
+                let instruction =
+                    Instruction::decode_with(word, address, &self.emulator.extensions);
+                let marker = match (
+                    address == self.emulator.pc,
+                    self.breakpoints.contains(&address),
+                ) {
+                    (true, _) => "=>",
+                    (false, true) => " *",
+                    (false, false) => "  ",
+                };
+                let symbol = self
+                    .symbolize(address)
+                    .map_or(String::new(), |symbol| format!(" <{}>", symbol));
+                format!(
+                    "{} {:08x}{}: {:08x}  {}",
+                    marker, address, symbol, word, instruction
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extensions::Extensions;
+
+    fn debugger(source: &str) -> Debugger {
+        let (regions, labels) =
+            crate::assemble_regions_with(&Extensions::default(), 0x1000, source).unwrap();
+        let mut emulator = Emulator::new(Extensions::default());
+        emulator.load(&regions);
+        Debugger::new(emulator, &regions, labels)
+    }
+
+    #[test]
+    fn debugger_commands() {
+        let mut debugger = debugger(
+            "set32 r4, 0x2000\naddi r1, r0, 3\nlbl loop\naddi r1, r1, -1\n\
+             st.d r0, r4, r1, 0x4\nsubs r0, r1, r0\nb.clr r1, 0, loop\nret.d\n",
+        );
+        assert_eq!(
+            debugger.execute("b loop").unwrap(),
+            "Breakpoint at 0x100c <loop>"
+        );
+        assert_eq!(
+            debugger.execute("c").unwrap(),
+            "Breakpoint at 0x100c <loop>\n=> 0000100c <loop>: 0021ffff  addi r1, r1, -0x1"
+        );
+        assert_eq!(debugger.emulator.registers[1], 3);
+
+        debugger.execute("d loop").unwrap();
+        debugger.execute("watch 0x2004 4").unwrap();
+        let output = debugger.execute("c").unwrap();
+        assert!(
+            output.starts_with("Watchpoint at 0x2004 written by 0x1010 <loop+0x4>\n=> 00001014"),
+            "{}",
+            output
+        );
+        assert_eq!(
+            debugger.execute("x 0x2004 4").unwrap(),
+            "00002004: 00 00 00 02"
+        );
+        assert!(debugger.execute("x 0xffffffff 0xffffffffffffffff").is_err());
+        assert!(debugger.execute("x 0x2004 0x1001").is_err());
+
+        debugger.execute("unwatch 0x2004").unwrap();
+        debugger.execute("set r1 1").unwrap();
+        assert!(debugger.execute("s 2").unwrap().contains("ret.d"));
+        assert_eq!(debugger.execute("c").unwrap(), "Returned");
+        assert!(debugger
+            .execute("regs")
+            .unwrap()
+            .contains("r4  0x0000000000002000"));
+        assert!(debugger
+            .execute("l loop 2")
+            .unwrap()
+            .contains("   0000100c <loop>:"));
+        assert!(debugger.execute("b nowhere").is_err());
+        assert!(debugger.execute("frobnicate").is_err());
+    }
+
+    #[test]
+    fn debugger_watch_top_of_memory() {
+        let mut debugger = debugger("addi r1, r0, -1\nst.b r1, r1, 0x0\nret.d\n");
+        debugger.execute("watch 0x2000").unwrap();
+        debugger.watchpoints.insert(u64::MAX - 1, u64::MAX);
+        assert!(debugger
+            .execute("c")
+            .unwrap()
+            .starts_with("Watchpoint at 0xfffffffffffffffe"));
+    }
+}
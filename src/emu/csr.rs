@@ -0,0 +1,203 @@
+//! Control and status registers.
+
+use std::{collections::BTreeMap, fmt, path::Path, sync::Arc};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+/// Computes the value of a CSR, given the value written if it's a write.
+///
+/// What a write returns is ignored.
+pub type CsrCallback = Arc<dyn Fn(u32, Option<u64>) -> u64 + Send + Sync>;
+
+/// What a CSR number is backed by.
+#[derive(Clone)]
+pub enum Csr {
+    /// A value that writes replace.
+    Stored(u64),
+    /// A value that writes leave alone.
+    Constant(u64),
+    Callback(CsrCallback),
+}
+
+impl fmt::Debug for Csr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Csr::Stored(value) => write!(f, "Stored({:#x})", value),
+            Csr::Constant(value) => write!(f, "Constant({:#x})", value),
+            Csr::Callback(_) => f.write_str("Callback"),
+        }
+    }
+}
+
+/// One `csr.r` or `csr.w`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct CsrAccess {
+    /// The address of the instruction.
+    pub address: u32,
+    pub csr: u32,
+    pub write: bool,
+    /// The value read or written.
+    pub value: u64,
+}
+
+impl fmt::Display for CsrAccess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let arrow = if self.write { "<-" } else { "->" };
+        write!(
+            f,
+            "{:08x}: csr[{:#x}] {} {:#x}",
+            self.address, self.csr, arrow, self.value
+        )
+    }
+}
+
+/// The CSRs the code may access and every access it made.
+///
+/// Accessing a CSR without a backing stops the emulator like an unknown instruction.
+#[derive(Debug, Clone, Default)]
+pub struct CsrSpace {
+    csrs: BTreeMap<u32, Csr>,
+    pub log: Vec<CsrAccess>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CsrsToml {
+    #[serde(default)]
+    csr: Vec<CsrToml>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CsrToml {
+    number: u32,
+    #[serde(default)]
+    value: u64,
+    #[serde(default)]
+    read_only: bool,
+}
+
+impl CsrSpace {
+    /// Backs `csr` by `backing`, replacing any earlier backing.
+    pub fn define(&mut self, csr: u32, backing: Csr) {
+        self.csrs.insert(csr, backing);
+    }
+
+    /// Backs `csr` by `callback`.
+    pub fn callback(
+        &mut self,
+        csr: u32,
+        callback: impl Fn(u32, Option<u64>) -> u64 + Send + Sync + 'static,
+    ) {
+        self.define(csr, Csr::Callback(Arc::new(callback)));
+    }
+
+    pub fn get(&self, csr: u32) -> Option<&Csr> {
+        self.csrs.get(&csr)
+    }
+
+    /// Reads `csr` for the instruction at `address`, `None` if it has no backing.
+    pub fn read(&mut self, address: u32, csr: u32) -> Option<u64> {
+        let value = match self.csrs.get(&csr)? {
+            Csr::Stored(value) | Csr::Constant(value) => *value,
+            Csr::Callback(callback) => callback(csr, None),
+        };
+        self.log.push(CsrAccess {
+            address,
+            csr,
+            write: false,
+            value,
+        });
+        Some(value)
+    }
+
+    /// Writes `csr` for the instruction at `address`, `None` if it has no backing.
+    pub fn write(&mut self, address: u32, csr: u32, value: u64) -> Option<()> {
+        match self.csrs.get_mut(&csr)? {
+            Csr::Stored(stored) => *stored = value,
+            Csr::Constant(_) => {}
+            Csr::Callback(callback) => {
+                callback(csr, Some(value));
+            }
+        }
+        self.log.push(CsrAccess {
+            address,
+            csr,
+            write: true,
+            value,
+        });
+        Some(())
+    }
+
+    pub fn from_toml(source: &str) -> anyhow::Result<Self> {
+        let file: CsrsToml = toml::from_str(source)?;
+        let mut csrs = Self::default();
+        for csr in file.csr {
+            let backing = match csr.read_only {
+                true => Csr::Constant(csr.value),
+                false => Csr::Stored(csr.value),
+            };
+            csrs.define(csr.number, backing);
+        }
+        Ok(csrs)
+    }
+
+    /// Adds the CSRs of a TOML file, replacing earlier ones with the same numbers.
+    pub fn load(&mut self, path: &Path) -> anyhow::Result<()> {
+        let source = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let csrs =
+            Self::from_toml(&source).with_context(|| format!("Bad CSRs {}", path.display()))?;
+        self.csrs.extend(csrs.csrs);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::emu::{tests::loaded, Stop};
+
+    #[test]
+    fn csr_backings() {
+        let mut csrs = CsrSpace::from_toml(
+            "[[csr]]\nnumber = 0x100\nvalue = 5\n\
+             [[csr]]\nnumber = 0x101\nvalue = 7\nread_only = true\n",
+        )
+        .unwrap();
+        let written = Arc::new(Mutex::new(vec![]));
+        let log = written.clone();
+        csrs.callback(0x200, move |_, value| {
+            log.lock().unwrap().extend(value);
+            0x1234
+        });
+
+        let mut emulator = loaded(
+            0x1000,
+            "csr.r r1, r0, 0x100\naddi r1, r1, 1\ncsr.w r0, r1, 0x100\ncsr.r r2, r0, 0x100\n\
+             csr.w r0, r1, 0x101\ncsr.r r3, r0, 0x101\ncsr.r r4, r0, 0x200\n\
+             csr.w r0, r2, 0x200\ncsr.r r5, r0, 0x300\n",
+        );
+        emulator.csrs = csrs;
+        let stop = emulator.run(100);
+
+        assert!(matches!(
+            stop,
+            Stop::Unknown {
+                address: 0x1020,
+                ..
+            }
+        ));
+        assert_eq!(emulator.registers[1..6], [6, 6, 7, 0x1234, 0]);
+        assert_eq!(*written.lock().unwrap(), [6]);
+        assert_eq!(emulator.csrs.log.len(), 7);
+        assert_eq!(
+            emulator.csrs.log[1].to_string(),
+            "00001008: csr[0x100] <- 0x6"
+        );
+        assert!(CsrSpace::from_toml("[[csr]]\nvalue = 1\n").is_err());
+    }
+}
@@ -0,0 +1,160 @@
+//! Delay slots after control transfers.
+
+use std::{fmt, str::FromStr};
+
+use anyhow::{bail, Context};
+
+use crate::{instructions::Instruction, utils::parse_number};
+
+/// How many instructions after each kind of control transfer still execute
+/// before it takes effect. None do by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DelaySlots {
+    pub retd: u32,
+    pub jump: u32,
+    pub call: u32,
+    /// `b.t`, `b.f`, `b.set` and `b.clr`.
+    pub branch: u32,
+}
+
+impl DelaySlots {
+    /// The delay slots of `instruction`, `None` if it doesn't transfer control.
+    pub fn of(&self, instruction: &Instruction) -> Option<u32> {
+        use Instruction::*;
+
+        match instruction {
+            Retd => Some(self.retd),
+            Jump(_) => Some(self.jump),
+            Call(_) => Some(self.call),
+            Bt(..) | Bf(..) | Bset(..) | Bclr(..) => Some(self.branch),
+            _ => None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Parses `kind=n,...` with kinds `retd`, `jump`, `call`, `branch` and `all`.
+impl FromStr for DelaySlots {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut slots = Self::default();
+        for part in s.split(',') {
+            let (kind, count) = part.split_once('=').context("no '=' in delay slots")?;
+            let count = u32::try_from(parse_number(count)?)?;
+            match kind.trim() {
+                "retd" => slots.retd = count,
+                "jump" => slots.jump = count,
+                "call" => slots.call = count,
+                "branch" => slots.branch = count,
+                "all" => {
+                    slots = Self {
+                        retd: count,
+                        jump: count,
+                        call: count,
+                        branch: count,
+                    }
+                }
+                kind => bail!(
+                    "Unknown control transfer {}, expected retd, jump, call, branch or all",
+                    kind
+                ),
+            }
+        }
+        Ok(slots)
+    }
+}
+
+impl fmt::Display for DelaySlots {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "retd={},jump={},call={},branch={}",
+            self.retd, self.jump, self.call, self.branch
+        )
+    }
+}
+
+/// A control transfer waiting for its delay slots.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Pending {
+    pub target: u32,
+    /// The delay slots still to execute.
+    pub slots: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emu::{
+        semantics::{AluOp, Semantics},
+        tests::loaded,
+        Emulator, Stop,
+    };
+
+    fn run(slots: &str, source: &str) -> (Emulator, Stop) {
+        let mut emulator = loaded(0x1000, source);
+        emulator.delay_slots = slots.parse().unwrap();
+        let mut semantics = Semantics::default();
+        semantics.branch(0x1, "eq".parse().unwrap());
+        semantics.immediate(0x1, AluOp::Add, true);
+        emulator.semantics = semantics;
+        let stop = emulator.run(100);
+        (emulator, stop)
+    }
+
+    #[test]
+    fn delay_slot_execution() {
+        let source = "b.clr r0, 0, skip\naddi r1, r1, 1\naddi r2, r2, 1\n\
+                      lbl skip\njump back\naddi r3, r3, 1\naddi r4, r4, 1\n\
+                      lbl back\naddi r5, r5, 1\nret.d\naddi r6, r6, 1\n";
+        let (emulator, stop) = run("all=0", source);
+        assert_eq!(stop, Stop::Returned);
+        assert_eq!(emulator.registers[1..7], [0, 0, 0, 0, 1, 0]);
+
+        let (emulator, stop) = run("all=1", source);
+        assert_eq!(stop, Stop::Returned);
+        assert_eq!(emulator.registers[1..7], [1, 0, 1, 0, 1, 1]);
+
+        // The return address skips the delay slots.
+        let (emulator, _) = run(
+            "call=2",
+            "call sub\naddi r1, r1, 1\naddi r2, r2, 1\naddi r3, r3, 1\nlbl sub\njump sub\n",
+        );
+        assert_eq!(emulator.registers[1..4], [1, 1, 0]);
+        assert_eq!(emulator.link, 0x100c);
+
+        // Branches and a delay slot run through semantics rules.
+        let (emulator, stop) = run(
+            "all=1",
+            "subs r0, r0, r0\nb.t 0x1, r0, target\naddi r1, r1, 1\naddi r2, r2, 1\n\
+             lbl target\naddi r3, r3, 1\nb.f 0x1, r0, away\naddi r4, r4, 1\naddi r5, r5, 1\n\
+             jump end\nunk.i 0x1, r6, r6, 1\naddi r7, r7, 1\nlbl end\nret.d\n\
+             addi r8, r8, 1\nlbl away\naddi r9, r9, 1\nret.d\n",
+        );
+        assert_eq!(stop, Stop::Returned);
+        assert_eq!(emulator.registers[1..10], [1, 0, 1, 1, 1, 1, 0, 1, 0]);
+
+        // A branch not taken fills a delay slot like any other instruction.
+        let (emulator, stop) = run(
+            "jump=1,branch=1",
+            "jump end\nb.f 0x1, r0, away\naddi r1, r1, 1\naddi r2, r2, 1\nlbl end\nret.d\n\
+             lbl away\naddi r3, r3, 1\nret.d\n",
+        );
+        assert_eq!(stop, Stop::Returned);
+        assert_eq!(emulator.registers[1..4], [0, 0, 0]);
+
+        // A branch taken to the next instruction runs it twice.
+        let (emulator, stop) = run(
+            "branch=1",
+            "b.t 0x1, r0, next\nlbl next\naddi r1, r1, 1\nret.d\n",
+        );
+        assert_eq!(stop, Stop::Returned);
+        assert_eq!(emulator.registers[1], 2);
+
+        assert!("jump=1,loop=1".parse::<DelaySlots>().is_err());
+    }
+}
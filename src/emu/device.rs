@@ -0,0 +1,222 @@
+//! Memory-mapped stand-ins for hardware.
+
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{bail, ensure};
+
+use super::{trace::MemAccess, Memory};
+
+/// Something the code reads and writes at a range of addresses.
+///
+/// `offset` is relative to the start of the range and values are big-endian.
+pub trait Device: Send {
+    fn read(&mut self, offset: u64, len: usize) -> u64;
+    fn write(&mut self, offset: u64, len: usize, value: u64);
+
+    /// Reads without side effects, for debuggers. Zero unless implemented.
+    fn peek(&mut self, _offset: u64, _len: usize) -> u64 {
+        0
+    }
+}
+
+/// Plain memory.
+#[derive(Debug, Clone, Default)]
+pub struct Ram(pub Memory);
+
+impl Device for Ram {
+    fn read(&mut self, offset: u64, len: usize) -> u64 {
+        self.0.load(offset, len)
+    }
+
+    fn write(&mut self, offset: u64, len: usize, value: u64) {
+        self.0.store(offset, len, value)
+    }
+
+    fn peek(&mut self, offset: u64, len: usize) -> u64 {
+        self.read(offset, len)
+    }
+}
+
+/// Memory that ignores writes, e.g. a firmware image.
+#[derive(Debug, Clone, Default)]
+pub struct Rom(pub Vec<u8>);
+
+impl Device for Rom {
+    fn read(&mut self, offset: u64, len: usize) -> u64 {
+        (offset..offset + len as u64).fold(0, |value, offset| {
+            let byte = usize::try_from(offset)
+                .ok()
+                .and_then(|offset| self.0.get(offset));
+            value << 8 | byte.copied().unwrap_or(0) as u64
+        })
+    }
+
+    fn write(&mut self, _: u64, _: usize, _: u64) {}
+
+    fn peek(&mut self, offset: u64, len: usize) -> u64 {
+        self.read(offset, len)
+    }
+}
+
+/// Records every access before passing it on.
+#[derive(Debug, Clone, Default)]
+pub struct Logger<D> {
+    pub inner: D,
+    /// The accesses, with addresses being offsets.
+    pub log: Vec<MemAccess>,
+}
+
+impl<D: Device> Logger<D> {
+    pub fn new(inner: D) -> Self {
+        Self { inner, log: vec![] }
+    }
+}
+
+impl<D: Device> Device for Logger<D> {
+    fn read(&mut self, offset: u64, len: usize) -> u64 {
+        let value = self.inner.read(offset, len);
+        self.log.push(MemAccess {
+            write: false,
+            address: offset,
+            len,
+            value,
+        });
+        value
+    }
+
+    fn write(&mut self, offset: u64, len: usize, value: u64) {
+        self.inner.write(offset, len, value);
+        self.log.push(MemAccess {
+            write: true,
+            address: offset,
+            len,
+            value,
+        });
+    }
+
+    fn peek(&mut self, offset: u64, len: usize) -> u64 {
+        self.inner.peek(offset, len)
+    }
+}
+
+#[derive(Clone)]
+struct Mapping {
+    start: u64,
+    end: u64,
+    device: Arc<Mutex<dyn Device>>,
+}
+
+/// The devices mapped into the address space.
+///
+/// Clones share the devices.
+#[derive(Clone, Default)]
+pub struct Devices {
+    mappings: Vec<Mapping>,
+}
+
+impl fmt::Debug for Devices {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(
+                self.mappings
+                    .iter()
+                    .map(|mapping| mapping.start..mapping.end),
+            )
+            .finish()
+    }
+}
+
+impl Devices {
+    /// Maps `device` at `start..end`, returning a handle to inspect it with.
+    pub fn map<D: Device + 'static>(
+        &mut self,
+        start: u64,
+        end: u64,
+        device: D,
+    ) -> anyhow::Result<Arc<Mutex<D>>> {
+        ensure!(start < end, "Empty device range {:#x}..{:#x}", start, end);
+        if let Some(mapping) = self
+            .mappings
+            .iter()
+            .find(|mapping| start < mapping.end && mapping.start < end)
+        {
+            bail!(
+                "{:#x}..{:#x} overlaps the device at {:#x}..{:#x}",
+                start,
+                end,
+                mapping.start,
+                mapping.end
+            );
+        }
+        let device = Arc::new(Mutex::new(device));
+        self.mappings.push(Mapping {
+            start,
+            end,
+            device: device.clone(),
+        });
+        Ok(device)
+    }
+
+    /// The device at `address` and the offset into it.
+    fn find(&self, address: u64) -> Option<(&Mutex<dyn Device>, u64)> {
+        self.mappings
+            .iter()
+            .find(|mapping| (mapping.start..mapping.end).contains(&address))
+            .map(|mapping| (&*mapping.device, address - mapping.start))
+    }
+
+    /// Reads from the device at `address`, `None` if there is none.
+    pub fn read(&self, address: u64, len: usize) -> Option<u64> {
+        let (device, offset) = self.find(address)?;
+        Some(device.lock().unwrap().read(offset, len))
+    }
+
+    /// Peeks at the device at `address`, `None` if there is none.
+    pub fn peek(&self, address: u64, len: usize) -> Option<u64> {
+        let (device, offset) = self.find(address)?;
+        Some(device.lock().unwrap().peek(offset, len))
+    }
+
+    /// Writes to the device at `address`, `None` if there is none.
+    pub fn write(&self, address: u64, len: usize, value: u64) -> Option<()> {
+        let (device, offset) = self.find(address)?;
+        device.lock().unwrap().write(offset, len, value);
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emu::{tests::loaded, Stop};
+
+    #[test]
+    fn devices() {
+        let mut emulator = loaded(
+            0x10_0000,
+            "set32 r4, 0x20000\nld.d r1, r4, 0x4\nst.d r0, r4, r1, 0x0\nld.d r2, r4, 0x0\n\
+             set32 r5, 0x30000\nst.d r0, r5, r1, 0x8\nld.d r3, r5, 0x8\njump 0x2000\n",
+        );
+        let rom = Rom(vec![0, 0, 0, 0, 0x12, 0x34, 0x56, 0x78]);
+        emulator.devices.map(0x20000, 0x20008, rom).unwrap();
+        let log = Logger::new(Ram::default());
+        let log = emulator.devices.map(0x30000, 0x30010, log).unwrap();
+        // Code in a device runs too: a ret.d at 0x2000.
+        let ret = Rom(vec![0xfc, 0x00, 0x00, 0x2d]);
+        emulator.devices.map(0x2000, 0x2004, ret).unwrap();
+        assert!(emulator
+            .devices
+            .map(0x2002, 0x2010, Ram::default())
+            .is_err());
+
+        assert_eq!(emulator.run(100), Stop::Returned);
+        // The store to the ROM is ignored.
+        assert_eq!(emulator.registers[1..4], [0x12345678, 0, 0x12345678]);
+        let log = &log.lock().unwrap().log;
+        assert_eq!(log.len(), 2);
+        assert_eq!(log[0].to_string(), "[0x8]4 <- 0x12345678");
+    }
+}
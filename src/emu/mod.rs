@@ -0,0 +1,490 @@
+//! An emulator for the instructions whose behavior is known.
+
+pub mod csr;
+pub mod delay;
+pub mod device;
+pub mod semantics;
+pub mod trace;
+
+use std::{cell::RefCell, collections::BTreeMap, fmt};
+
+use crate::{
+    assembler::Region,
+    extensions::Extensions,
+    fields::{Field, Label, Reg, SetImm},
+    instructions::Instruction,
+    utils::parse_number,
+};
+use csr::CsrSpace;
+use delay::{DelaySlots, Pending};
+use device::Devices;
+use semantics::{Key, Semantics};
+use trace::{MemAccess, RegWrite, TraceEntry};
+
+const PAGE_SIZE: u64 = 0x1000;
+
+/// Sparse big-endian memory, reading zeros where nothing was written.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Memory {
+    pages: BTreeMap<u64, Box<[u8; PAGE_SIZE as usize]>>,
+}
+
+impl Memory {
+    pub fn read_byte(&self, address: u64) -> u8 {
+        self.pages
+            .get(&(address / PAGE_SIZE))
+            .map_or(0, |page| page[(address % PAGE_SIZE) as usize])
+    }
+
+    pub fn write_byte(&mut self, address: u64, value: u8) {
+        let page = self
+            .pages
+            .entry(address / PAGE_SIZE)
+            .or_insert_with(|| Box::new([0; PAGE_SIZE as usize]));
+        page[(address % PAGE_SIZE) as usize] = value;
+    }
+
+    pub fn read(&self, address: u64, len: usize) -> Vec<u8> {
+        (0..len as u64)
+            .map(|offset| self.read_byte(address.wrapping_add(offset)))
+            .collect()
+    }
+
+    pub fn write(&mut self, address: u64, bytes: &[u8]) {
+        for (offset, byte) in bytes.iter().enumerate() {
+            self.write_byte(address.wrapping_add(offset as u64), *byte);
+        }
+    }
+
+    /// Reads a `len` byte big-endian value.
+    pub fn load(&self, address: u64, len: usize) -> u64 {
+        self.read(address, len)
+            .into_iter()
+            .fold(0, |value, byte| value << 8 | byte as u64)
+    }
+
+    /// Writes the low `len` bytes of `value` big-endian.
+    pub fn store(&mut self, address: u64, len: usize, value: u64) {
+        self.write(address, &value.to_be_bytes()[8 - len..]);
+    }
+}
+
+/// The operands and result of the last `subs`, which branches test.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Flags {
+    pub lhs: u64,
+    pub rhs: u64,
+    pub result: u64,
+}
+
+/// Why the emulator stopped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stop {
+    /// `ret.d` returned to the caller of the code.
+    Returned,
+    /// The step limit was reached.
+    StepLimit,
+    /// The word at `address` has no known behavior.
+    Unknown {
+        address: u32,
+        word: u32,
+        instruction: Instruction,
+    },
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stop::Returned => f.write_str("Returned"),
+            Stop::StepLimit => f.write_str("Step limit reached"),
+            Stop::Unknown {
+                address,
+                word,
+                instruction,
+            } => write!(
+                f,
+                "Unknown behavior of `{}` ({:08x}) at {:#x}",
+                instruction, word, address
+            ),
+        }
+    }
+}
+
+/// Where `ret.d` goes when the code returns to its caller.
+pub const RETURN_ADDRESS: u32 = 0xffff_fffc;
+
+/// The state of the machine.
+///
+/// Which register `call` saves the return address in is unknown, so it is kept
+/// in `link` instead, starting out as `RETURN_ADDRESS`.
+#[derive(Debug, Clone)]
+pub struct Emulator {
+    pub registers: [u64; 32],
+    pub pc: u32,
+    pub link: u64,
+    pub flags: Flags,
+    pub memory: Memory,
+    pub extensions: Extensions,
+    /// What unknown instructions are assumed to do.
+    pub semantics: Semantics,
+    pub csrs: CsrSpace,
+    /// Devices mapped over `memory`.
+    pub devices: Devices,
+    pub delay_slots: DelaySlots,
+    /// The control transfer whose delay slots are executing.
+    pending: Option<Pending>,
+    /// The target of the control transfer the current instruction takes.
+    transfer: Option<u32>,
+    /// The number of instructions executed.
+    pub steps: u64,
+    /// The accesses of the instruction being traced.
+    recording: RefCell<Option<TraceEntry>>,
+}
+
+impl Emulator {
+    pub fn new(extensions: Extensions) -> Self {
+        Self {
+            registers: [0; 32],
+            pc: 0,
+            link: RETURN_ADDRESS as u64,
+            flags: Flags::default(),
+            memory: Memory::default(),
+            extensions,
+            semantics: Semantics::default(),
+            csrs: CsrSpace::default(),
+            devices: Devices::default(),
+            delay_slots: DelaySlots::default(),
+            pending: None,
+            transfer: None,
+            steps: 0,
+            recording: RefCell::new(None),
+        }
+    }
+
+    /// Copies `regions` into memory and starts at the first one.
+    pub fn load(&mut self, regions: &[Region]) {
+        for region in regions {
+            self.memory.write(region.address as u64, &region.code);
+        }
+        if let Some(region) = regions.first() {
+            self.pc = region.address;
+        }
+    }
+
+    pub fn reg(&self, reg: Reg) -> u64 {
+        let value = match reg.0 {
+            0 => 0,
+            n => self.registers[n as usize],
+        };
+        if let Some(entry) = self.recording.borrow_mut().as_mut() {
+            entry.read(reg.0, value);
+        }
+        value
+    }
+
+    pub fn set_reg(&mut self, reg: Reg, value: u64) {
+        if reg.0 == 0 {
+            return;
+        }
+        let old = std::mem::replace(&mut self.registers[reg.0 as usize], value);
+        if let Some(entry) = self.recording.get_mut() {
+            entry.writes.push(RegWrite {
+                reg: reg.0,
+                old,
+                new: value,
+            });
+        }
+    }
+
+    fn read_bus(&self, address: u64, len: usize) -> u64 {
+        self.devices
+            .read(address, len)
+            .unwrap_or_else(|| self.memory.load(address, len))
+    }
+
+    /// Reads a `len` byte value without side effects on devices or traces.
+    pub fn peek(&self, address: u64, len: usize) -> u64 {
+        self.devices
+            .peek(address, len)
+            .unwrap_or_else(|| self.memory.load(address, len))
+    }
+
+    /// Loads a `len` byte value like the code does, recording it when tracing.
+    pub fn load_memory(&self, address: u64, len: usize) -> u64 {
+        let value = self.read_bus(address, len);
+        if let Some(entry) = self.recording.borrow_mut().as_mut() {
+            entry.memory.push(MemAccess {
+                write: false,
+                address,
+                len,
+                value,
+            });
+        }
+        value
+    }
+
+    /// Stores a `len` byte value like the code does, recording it when tracing.
+    pub fn store_memory(&mut self, address: u64, len: usize, value: u64) {
+        if self.devices.write(address, len, value).is_none() {
+            self.memory.store(address, len, value);
+        }
+        if let Some(entry) = self.recording.get_mut() {
+            entry.memory.push(MemAccess {
+                write: true,
+                address,
+                len,
+                value,
+            });
+        }
+    }
+
+    fn address(&self, rs: Reg, offset: i64) -> u64 {
+        self.reg(rs).wrapping_add(offset as u64)
+    }
+
+    /// The instruction at the program counter.
+    pub fn fetch(&self) -> (u32, Instruction) {
+        let word = self.read_bus(self.pc as u64, 4) as u32;
+        (
+            word,
+            Instruction::decode_with(word, self.pc, &self.extensions),
+        )
+    }
+
+    /// Executes one instruction.
+    pub fn step(&mut self) -> Result<(), Stop> {
+        use Instruction::*;
+
+        if self.pc == RETURN_ADDRESS {
+            return Err(Stop::Returned);
+        }
+        let (word, instruction) = self.fetch();
+        self.transfer = None;
+        let fallthrough = self.pc.wrapping_add(4);
+        let mut next = fallthrough;
+        match &instruction {
+            Addi(rd, rs, simm) => self.set_reg(rd.0, self.address(rs.0, simm.0)),
+            Set0(rd, rs, imm) => self.set_reg(rd.0, set(self.reg(rs.0), 0, imm)),
+            Set1(rd, rs, imm) => self.set_reg(rd.0, set(self.reg(rs.0), 1, imm)),
+            Set2(rd, rs, imm) => self.set_reg(rd.0, set(self.reg(rs.0), 2, imm)),
+            Set3(rd, rs, imm) => self.set_reg(rd.0, set(self.reg(rs.0), 3, imm)),
+            // What `rs` of `csr.r` and `rd` of `csr.w` do is unknown.
+            CsrR(rd, _, csr) => match self.csrs.read(self.pc, csr.0 as u32) {
+                Some(value) => self.set_reg(rd.0, value),
+                None => return Err(self.unknown(word, &instruction)),
+            },
+            CsrW(_, rs, csr) => {
+                let value = self.reg(rs.0);
+                if self.csrs.write(self.pc, csr.0 as u32, value).is_none() {
+                    return Err(self.unknown(word, &instruction));
+                }
+            }
+            Ldb(rd, rs, off) => {
+                let value = self.load_memory(self.address(rs.0, off.0), 1);
+                self.set_reg(rd.0, value)
+            }
+            Ldq(rd, rs, off) => {
+                let value = self.load_memory(self.address(rs.0, off.value()), 8);
+                self.set_reg(rd.0, value)
+            }
+            Ldd(rd, rs, off) => {
+                let value = self.load_memory(self.address(rs.0, off.value()), 4);
+                self.set_reg(rd.0, value)
+            }
+            Lduw(rd, rs, off) => {
+                let value = self.load_memory(self.address(rs.0, off.value()), 4);
+                self.set_reg(rd.0, value << 32 | self.reg(rd.0) & 0xffff_ffff)
+            }
+            Ldlw(rd, rs, off) => {
+                let value = self.load_memory(self.address(rs.0, off.value()), 4);
+                self.set_reg(rd.0, self.reg(rd.0) & !0xffff_ffff | value)
+            }
+            Stb(rt, rs, off) => {
+                let value = self.reg(rt.0);
+                self.store_memory(self.address(rs.0, off.value()), 1, value)
+            }
+            Std(_, rs, rt, off) => {
+                let value = self.reg(rt.0);
+                self.store_memory(self.address(rs.0, off.value()), 4, value)
+            }
+            Stq(_, rs, rt, off) => {
+                let value = self.reg(rt.0);
+                self.store_memory(self.address(rs.0, off.value()), 8, value)
+            }
+            Jump(target) => self.transfer(target_address(&target.0)),
+            Call(target) => {
+                self.link = next.wrapping_add(4 * self.delay_slots.call) as u64;
+                self.transfer(target_address(&target.0))
+            }
+            Retd => self.transfer(self.link as u32),
+            // Bit 0 is assumed to be the least significant one.
+            Bset(rs, bitsel, target) if self.reg(rs.0) >> bitsel.0 .0 & 1 == 1 => {
+                self.transfer(target_address(&target.0))
+            }
+            Bclr(rs, bitsel, target) if self.reg(rs.0) >> bitsel.0 .0 & 1 == 0 => {
+                self.transfer(target_address(&target.0))
+            }
+            Bset(..) | Bclr(..) => {}
+            Add(rd, rs, rt) => self.set_reg(rd.0, self.reg(rs.0).wrapping_add(self.reg(rt.0))),
+            Sub(rd, rs, rt) => self.set_reg(rd.0, self.reg(rs.0).wrapping_sub(self.reg(rt.0))),
+            Subs(rd, rs, rt) => {
+                let (lhs, rhs) = (self.reg(rs.0), self.reg(rt.0));
+                let result = lhs.wrapping_sub(rhs);
+                self.flags = Flags { lhs, rhs, result };
+                self.set_reg(rd.0, result)
+            }
+            _ => {
+                let address = self.pc;
+                let Some(handler) = Key::of(&instruction).and_then(|key| self.semantics.get(key))
+                else {
+                    return Err(self.unknown(word, &instruction));
+                };
+                self.pc = next;
+                if let Err(stop) = handler(self, word, &instruction) {
+                    self.pc = address;
+                    return Err(stop);
+                }
+                next = self.pc;
+            }
+        }
+        if let Some(target) = self.transfer.take() {
+            // A transfer taken in a delay slot replaces the pending one.
+            let slots = self.delay_slots.of(&instruction).unwrap_or(0);
+            self.pending = (slots > 0).then_some(Pending { target, slots });
+            next = if slots > 0 { fallthrough } else { target };
+        } else if let Some(pending) = self.pending.take() {
+            match pending.slots {
+                1 => next = pending.target,
+                slots => {
+                    self.pending = Some(Pending {
+                        slots: slots - 1,
+                        ..pending
+                    })
+                }
+            }
+        }
+        self.pc = next;
+        self.steps += 1;
+        Ok(())
+    }
+
+    /// Transfers control to `target` once the delay slots of the current
+    /// instruction have executed, e.g. from the semantics of a branch.
+    pub fn transfer(&mut self, target: u32) {
+        self.transfer = Some(target);
+    }
+
+    fn unknown(&self, word: u32, instruction: &Instruction) -> Stop {
+        Stop::Unknown {
+            address: self.pc,
+            word,
+            instruction: instruction.clone(),
+        }
+    }
+
+    /// Executes instructions until one stops the emulator or `max_steps` have run.
+    pub fn run(&mut self, max_steps: u64) -> Stop {
+        for _ in 0..max_steps {
+            if let Err(stop) = self.step() {
+                return stop;
+            }
+        }
+        Stop::StepLimit
+    }
+}
+
+/// `value` with halfword `hw` replaced, `hw` 0 being bits 63:48.
+fn set(value: u64, hw: u32, imm: &SetImm) -> u64 {
+    let SetImm::Imm(imm) = imm else {
+        unreachable!("decoded immediates are numbers")
+    };
+    let shift = 48 - 16 * hw;
+    value & !(0xffff << shift) | imm.0 << shift
+}
+
+fn target_address(label: &Label) -> u32 {
+    parse_number(&label.0).expect("decoded targets are numbers") as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An emulator with `source` assembled at `base_addr` loaded.
+    pub(crate) fn loaded(base_addr: u32, source: &str) -> Emulator {
+        let (regions, _) =
+            crate::assemble_regions_with(&Extensions::default(), base_addr, source).unwrap();
+        let mut emulator = Emulator::new(Extensions::default());
+        emulator.load(&regions);
+        emulator
+    }
+
+    fn run(source: &str) -> (Emulator, Stop) {
+        let mut emulator = loaded(0x1000, source);
+        let stop = emulator.run(100);
+        (emulator, stop)
+    }
+
+    #[test]
+    fn emu_arithmetic() {
+        let (emulator, stop) = run(
+            "set64 r1, 0x1122334455667788\naddi r2, r1, -8\nadd r3, r1, r2\nsub r4, r2, r1\n\
+             subs r5, r2, r2\naddi r0, r0, 1\nset1 r6, r1, 0xabcd\nret.d\n",
+        );
+        assert_eq!(stop, Stop::Returned);
+        assert_eq!(emulator.registers[1], 0x1122334455667788);
+        assert_eq!(emulator.registers[2], 0x1122334455667780);
+        assert_eq!(emulator.registers[3], 0x22446688aaccef08);
+        assert_eq!(emulator.registers[4], -8i64 as u64);
+        assert_eq!(emulator.registers[5], 0);
+        assert_eq!(emulator.registers[6], 0x1122abcd55667788);
+        assert_eq!(emulator.flags.lhs, 0x1122334455667780);
+        assert_eq!(emulator.reg(Reg(0)), 0);
+    }
+
+    #[test]
+    fn emu_memory() {
+        let (emulator, _) = run(
+            "set64 r1, 0x1122334455667788\nset32 r4, 0x8000\nst.q r0, r4, r1, 0x8\n\
+             st.d r0, r4, r1, 0x10\nst.b r1, r4, 0x14\nld.d r2, r4, 0xc\nld.uw r2, r4, 0x10\n\
+             ld.b r3, r4, 0x14\nld.q r5, r4, 0x8\nret.d\n",
+        );
+        assert_eq!(
+            emulator.memory.read(0x8008, 13),
+            [0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x55, 0x66, 0x77, 0x88, 0x88]
+        );
+        assert_eq!(emulator.registers[2], 0x5566778855667788);
+        assert_eq!(emulator.registers[3], 0x88);
+        assert_eq!(emulator.registers[5], 0x1122334455667788);
+    }
+
+    #[test]
+    fn emu_control_flow() {
+        let (emulator, stop) = run(
+            "call func\naddi r2, r0, 2\njump done\nlbl func\naddi r1, r0, 1\nret.d\nlbl done\n\
+             dword 0xfc000123\n",
+        );
+        assert_eq!(emulator.registers[1..3], [1, 2]);
+        assert_eq!(emulator.steps, 5);
+        assert_eq!(emulator.pc, 0x1014);
+        assert_eq!(
+            stop.to_string(),
+            "Unknown behavior of `alu.r 0x123, r0, r0, r0` (fc000123) at 0x1014"
+        );
+
+        assert_eq!(run("lbl loop\njump loop\n").1, Stop::StepLimit);
+
+        let (emulator, stop) = run(
+            "addi r1, r0, 4\nb.set r1, 0x2, skip\naddi r2, r0, 1\nlbl skip\nb.clr r1, 0x2, end\n\
+             addi r3, r0, 1\nlbl end\nsubs r0, r1, r1\nb.t 0x1, r0, end\n",
+        );
+        assert_eq!(emulator.registers[2..4], [0, 1]);
+        assert!(matches!(
+            stop,
+            Stop::Unknown {
+                address: 0x1018,
+                ..
+            }
+        ));
+    }
+}
@@ -0,0 +1,475 @@
+//! User supplied behavior for instructions the emulator doesn't know.
+
+use std::{collections::BTreeMap, fmt, path::Path, str::FromStr, sync::Arc};
+
+use anyhow::{bail, ensure, Context};
+use serde::Deserialize;
+
+use super::{Emulator, Flags, Stop};
+use crate::{
+    fields::{Field, Operand, Rd, Rs, Rt, StoreOff14},
+    instructions::Instruction,
+};
+
+macro_rules! alu_ops {
+    ($($(#[doc = $doc:literal])* $variant:ident $name:literal |$a:ident, $b:ident| $body:expr;)*) => {
+        /// A binary operation on 64-bit values that an unknown instruction may perform.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+        pub enum AluOp {
+            $($(#[doc = $doc])* $variant,)*
+        }
+
+        impl AluOp {
+            pub const ALL: &[AluOp] = &[$(AluOp::$variant,)*];
+
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(AluOp::$variant => $name,)*
+                }
+            }
+
+            pub fn apply(self, a: u64, b: u64) -> u64 {
+                match self {
+                    $(AluOp::$variant => (|$a: u64, $b: u64| $body)(a, b),)*
+                }
+            }
+        }
+    };
+}
+
+alu_ops! {
+    Add "add" |a, b| a.wrapping_add(b);
+    Sub "sub" |a, b| a.wrapping_sub(b);
+    /// `b - a`.
+    Rsub "rsub" |a, b| b.wrapping_sub(a);
+    And "and" |a, b| a & b;
+    Or "or" |a, b| a | b;
+    Xor "xor" |a, b| a ^ b;
+    Nor "nor" |a, b| !(a | b);
+    Nand "nand" |a, b| !(a & b);
+    Xnor "xnor" |a, b| !(a ^ b);
+    /// `a & !b`.
+    Andn "andn" |a, b| a & !b;
+    /// `a | !b`.
+    Orn "orn" |a, b| a | !b;
+    Shl "shl" |a, b| a << (b & 63);
+    Shr "shr" |a, b| a >> (b & 63);
+    Sar "sar" |a, b| ((a as i64) >> (b & 63)) as u64;
+    Rotl "rotl" |a, b| a.rotate_left((b & 63) as u32);
+    Rotr "rotr" |a, b| a.rotate_right((b & 63) as u32);
+    Mul "mul" |a, b| a.wrapping_mul(b);
+    /// The upper 64 bits of the unsigned product.
+    Mulhu "mulhu" |a, b| ((a as u128 * b as u128) >> 64) as u64;
+    Min "min" |a, b| (a as i64).min(b as i64) as u64;
+    Max "max" |a, b| (a as i64).max(b as i64) as u64;
+    Minu "minu" |a, b| a.min(b);
+    Maxu "maxu" |a, b| a.max(b);
+    /// 1 if `a < b` signed, else 0.
+    Slt "slt" |a, b| ((a as i64) < (b as i64)) as u64;
+    /// 1 if `a < b` unsigned, else 0.
+    Sltu "sltu" |a, b| (a < b) as u64;
+    Eq "eq" |a, b| (a == b) as u64;
+    Ne "ne" |a, b| (a != b) as u64;
+    /// `a`, ignoring `b`.
+    First "first" |a, _b| a;
+    /// `b`, ignoring `a`.
+    Second "second" |_a, b| b;
+}
+
+impl AluOp {
+    /// Like `apply` on 32-bit operands, shifting and rotating within 32 bits.
+    pub fn apply32(self, a: u32, b: u32) -> u32 {
+        match self {
+            AluOp::Shl => a << (b & 31),
+            AluOp::Shr => a >> (b & 31),
+            AluOp::Sar => ((a as i32) >> (b & 31)) as u32,
+            AluOp::Rotl => a.rotate_left(b & 31),
+            AluOp::Rotr => a.rotate_right(b & 31),
+            AluOp::Mulhu => ((a as u64 * b as u64) >> 32) as u32,
+            AluOp::Min => (a as i32).min(b as i32) as u32,
+            AluOp::Max => (a as i32).max(b as i32) as u32,
+            AluOp::Slt => ((a as i32) < (b as i32)) as u32,
+            _ => self.apply(a as u64, b as u64) as u32,
+        }
+    }
+}
+
+impl FromStr for AluOp {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match AluOp::ALL.iter().find(|op| op.name() == s) {
+            Some(op) => Ok(*op),
+            None => bail!("Unknown operation: {}", s),
+        }
+    }
+}
+
+impl fmt::Display for AluOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+macro_rules! conditions {
+    ($($(#[doc = $doc:literal])* $variant:ident $name:literal |$f:ident| $body:expr;)*) => {
+        /// A condition on the flags that a branch `cmpop` may test.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+        pub enum Condition {
+            $($(#[doc = $doc])* $variant,)*
+        }
+
+        impl Condition {
+            pub const ALL: &[Condition] = &[$(Condition::$variant,)*];
+
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(Condition::$variant => $name,)*
+                }
+            }
+
+            pub fn holds(self, flags: Flags) -> bool {
+                match self {
+                    $(Condition::$variant => (|$f: Flags| $body)(flags),)*
+                }
+            }
+        }
+    };
+}
+
+conditions! {
+    Eq "eq" |f| f.lhs == f.rhs;
+    Ne "ne" |f| f.lhs != f.rhs;
+    Lt "lt" |f| (f.lhs as i64) < (f.rhs as i64);
+    Ge "ge" |f| (f.lhs as i64) >= (f.rhs as i64);
+    Gt "gt" |f| (f.lhs as i64) > (f.rhs as i64);
+    Le "le" |f| (f.lhs as i64) <= (f.rhs as i64);
+    /// Unsigned `lhs < rhs`, which is also a borrow out of `lhs - rhs`, so
+    /// `geu` doubles as its carry.
+    Ltu "ltu" |f| f.lhs < f.rhs;
+    Geu "geu" |f| f.lhs >= f.rhs;
+    Gtu "gtu" |f| f.lhs > f.rhs;
+    Leu "leu" |f| f.lhs <= f.rhs;
+    Zero "zero" |f| f.result == 0;
+    Nonzero "nonzero" |f| f.result != 0;
+    Neg "neg" |f| (f.result as i64) < 0;
+    Nonneg "nonneg" |f| (f.result as i64) >= 0;
+    /// Signed overflow of `lhs - rhs`.
+    Overflow "overflow" |f| (f.lhs as i64).overflowing_sub(f.rhs as i64).1;
+    Nooverflow "nooverflow" |f| !(f.lhs as i64).overflowing_sub(f.rhs as i64).1;
+    Always "always" |_f| true;
+    Never "never" |_f| false;
+}
+
+impl FromStr for Condition {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match Condition::ALL
+            .iter()
+            .find(|condition| condition.name() == s)
+        {
+            Some(condition) => Ok(*condition),
+            None => bail!("Unknown condition: {}", s),
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Which instructions a handler is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Key {
+    /// `alu.r` with this funct.
+    Funct(u32),
+    /// `unk.i`, `unk.r` or `unk.st` with this opcode.
+    Opcode(u32),
+    /// `b.t` and `b.f` with this cmpop.
+    Cmpop(u32),
+}
+
+impl Key {
+    pub fn of(instruction: &Instruction) -> Option<Self> {
+        match instruction {
+            Instruction::Alur(funct, ..) => Some(Key::Funct(funct.0 .0 as u32)),
+            Instruction::Unki(opcode, ..)
+            | Instruction::Unkr(opcode, ..)
+            | Instruction::Unkst(opcode, ..) => Some(Key::Opcode(opcode.0 .0 as u32)),
+            Instruction::Bt(cmpop, ..) | Instruction::Bf(cmpop, ..) => {
+                Some(Key::Cmpop(cmpop.0 .0 as u32))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Executes an instruction given its word and how it decodes, the program
+/// counter already pointing past it.
+///
+/// Words with an unknown opcode always decode as `unk.i`, so handlers for
+/// other formats take their fields from the word.
+pub type Handler = Arc<dyn Fn(&mut Emulator, u32, &Instruction) -> Result<(), Stop> + Send + Sync>;
+
+/// Hypotheses about what unknown instructions do.
+///
+/// ```toml
+/// [[alu]]
+/// funct = 0x00c
+/// op = "mul"
+///
+/// [[immediate]]
+/// opcode = 0x01
+/// op = "or"
+/// signed = false
+///
+/// [[register]]
+/// opcode = 0x3e
+/// op = "xor"
+///
+/// [[store]]
+/// opcode = 0x1c
+/// bytes = 2
+///
+/// [[branch]]
+/// cmpop = 0x1
+/// condition = "eq"
+/// ```
+///
+/// `alu` and `register` rules compute `rd = op(rs, rt)`, `immediate` rules
+/// `rd = op(rs, imm)`, `store` rules store the low `bytes` of `rt` at
+/// `rs + off`, and `branch` rules make `b.t cmpop` branch when `condition`
+/// holds and `b.f cmpop` when it doesn't.
+#[derive(Clone, Default)]
+pub struct Semantics {
+    handlers: BTreeMap<Key, Handler>,
+}
+
+impl fmt::Debug for Semantics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.handlers.keys()).finish()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SemanticsToml {
+    #[serde(default)]
+    alu: Vec<OpRuleToml>,
+    #[serde(default)]
+    immediate: Vec<OpRuleToml>,
+    #[serde(default)]
+    register: Vec<OpRuleToml>,
+    #[serde(default)]
+    store: Vec<StoreRuleToml>,
+    #[serde(default)]
+    branch: Vec<BranchRuleToml>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct OpRuleToml {
+    funct: Option<u32>,
+    opcode: Option<u32>,
+    op: String,
+    #[serde(default)]
+    signed: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StoreRuleToml {
+    opcode: u32,
+    bytes: usize,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BranchRuleToml {
+    cmpop: u32,
+    condition: String,
+}
+
+impl Semantics {
+    pub fn get(&self, key: Key) -> Option<Handler> {
+        self.handlers.get(&key).cloned()
+    }
+
+    /// Registers `handler`, replacing any earlier one for `key`.
+    pub fn define(
+        &mut self,
+        key: Key,
+        handler: impl Fn(&mut Emulator, u32, &Instruction) -> Result<(), Stop> + Send + Sync + 'static,
+    ) {
+        self.handlers.insert(key, Arc::new(handler));
+    }
+
+    /// Makes `alu.r funct` compute `rd = op(rs, rt)`.
+    pub fn alu(&mut self, funct: u32, op: AluOp) {
+        self.define(Key::Funct(funct), move |emu, _, instruction| {
+            if let Instruction::Alur(_, rd, rs, rt) = instruction {
+                emu.set_reg(rd.0, op.apply(emu.reg(rs.0), emu.reg(rt.0)));
+            }
+            Ok(())
+        });
+    }
+
+    /// Makes `unk.i opcode` compute `rd = op(rs, imm)`.
+    pub fn immediate(&mut self, opcode: u32, op: AluOp, signed: bool) {
+        self.define(Key::Opcode(opcode), move |emu, _, instruction| {
+            if let Instruction::Unki(_, rd, rs, imm) = instruction {
+                let imm = match signed {
+                    true => imm.0 as i16 as u64,
+                    false => imm.0,
+                };
+                emu.set_reg(rd.0, op.apply(emu.reg(rs.0), imm));
+            }
+            Ok(())
+        });
+    }
+
+    /// Makes `unk.r opcode` compute `rd = op(rs, rt)`.
+    pub fn register(&mut self, opcode: u32, op: AluOp) {
+        self.define(Key::Opcode(opcode), move |emu, word, _| {
+            let rd = <Rd as Operand>::decode(word, 0);
+            let (rs, rt) = (
+                <Rs as Operand>::decode(word, 0),
+                <Rt as Operand>::decode(word, 0),
+            );
+            emu.set_reg(rd.0, op.apply(emu.reg(rs.0), emu.reg(rt.0)));
+            Ok(())
+        });
+    }
+
+    /// Makes `unk.st opcode` store the low `bytes` of `rt` at `rs + off`.
+    pub fn store(&mut self, opcode: u32, bytes: usize) {
+        self.define(Key::Opcode(opcode), move |emu, word, _| {
+            let (rs, rt) = (
+                <Rs as Operand>::decode(word, 0),
+                <Rt as Operand>::decode(word, 0),
+            );
+            let off = <StoreOff14 as Operand>::decode(word, 0);
+            let address = emu.reg(rs.0).wrapping_add(off.value() as u64);
+            let value = emu.reg(rt.0);
+            emu.store_memory(address, bytes, value);
+            Ok(())
+        });
+    }
+
+    /// Makes `b.t cmpop` branch when `condition` holds, and `b.f cmpop` when it doesn't.
+    pub fn branch(&mut self, cmpop: u32, condition: Condition) {
+        self.define(Key::Cmpop(cmpop), move |emu, _, instruction| {
+            let (expected, target) = match instruction {
+                Instruction::Bt(_, _, target) => (true, target),
+                Instruction::Bf(_, _, target) => (false, target),
+                _ => return Ok(()),
+            };
+            if condition.holds(emu.flags) == expected {
+                emu.transfer(super::target_address(&target.0));
+            }
+            Ok(())
+        });
+    }
+
+    pub fn from_toml(source: &str) -> anyhow::Result<Self> {
+        let file: SemanticsToml = toml::from_str(source)?;
+        let mut semantics = Self::default();
+        for rule in file.alu {
+            let funct = rule.funct.context("An alu rule needs a funct")?;
+            semantics.alu(funct, rule.op.parse()?);
+        }
+        for rule in file.immediate {
+            let opcode = rule.opcode.context("An immediate rule needs an opcode")?;
+            semantics.immediate(opcode, rule.op.parse()?, rule.signed);
+        }
+        for rule in file.register {
+            let opcode = rule.opcode.context("A register rule needs an opcode")?;
+            semantics.register(opcode, rule.op.parse()?);
+        }
+        for rule in file.store {
+            ensure!(
+                (1..=8).contains(&rule.bytes),
+                "A store rule stores 1 to 8 bytes"
+            );
+            semantics.store(rule.opcode, rule.bytes);
+        }
+        for rule in file.branch {
+            semantics.branch(rule.cmpop, rule.condition.parse()?);
+        }
+        Ok(semantics)
+    }
+
+    /// Adds the rules of a TOML file, replacing earlier ones for the same instructions.
+    pub fn load(&mut self, path: &Path) -> anyhow::Result<()> {
+        let source = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let semantics = Self::from_toml(&source)
+            .with_context(|| format!("Bad semantics {}", path.display()))?;
+        self.handlers.extend(semantics.handlers);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emu::{tests::loaded, Emulator};
+
+    fn run(semantics: Semantics, source: &str) -> (Emulator, Stop) {
+        let mut emulator = loaded(0, source);
+        emulator.semantics = semantics;
+        let stop = emulator.run(100);
+        (emulator, stop)
+    }
+
+    #[test]
+    fn semantics_rules() {
+        let semantics = Semantics::from_toml(
+            "[[alu]]\nfunct = 0x00c\nop = \"mul\"\n\
+             [[immediate]]\nopcode = 0x01\nop = \"or\"\nsigned = true\n\
+             [[store]]\nopcode = 0x1c\nbytes = 2\n\
+             [[branch]]\ncmpop = 0x1\ncondition = \"ltu\"\n",
+        )
+        .unwrap();
+        let (emulator, stop) = run(
+            semantics,
+            "addi r1, r0, 6\naddi r2, r0, 7\nalu.r 0xc, r3, r1, r2\nunk.i 0x1, r4, r1, 0x8000\n\
+             addi r5, r0, 0x100\nunk.st 0x1c, r3, r5, 0x4, 0x0\nsubs r0, r1, r2\n\
+             b.t 0x1, r0, end\naddi r6, r0, 1\nlbl end\nsubs r0, r2, r1\nb.f 0x1, r0, out\n\
+             addi r7, r0, 1\nlbl out\nret.d\n",
+        );
+        assert_eq!(stop, Stop::Returned);
+        assert_eq!(emulator.registers[3], 42);
+        assert_eq!(emulator.registers[4], 0xffff_ffff_ffff_8006);
+        assert_eq!(emulator.memory.read(0x104, 2), [0, 42]);
+        assert_eq!(emulator.registers[6..8], [0, 0]);
+
+        assert!(Semantics::from_toml("[[alu]]\nfunct = 1\nop = \"frobnicate\"\n").is_err());
+        assert!(Semantics::from_toml("[[immediate]]\nfunct = 1\nop = \"or\"\n").is_err());
+    }
+
+    #[test]
+    fn semantics_closure() {
+        let mut semantics = Semantics::default();
+        semantics.define(
+            Key::Opcode(0x3e),
+            |emu, word, instruction| match instruction {
+                Instruction::Unki(_, rd, _, _) => {
+                    emu.set_reg(rd.0, (word & 0x7ff) as u64);
+                    Ok(())
+                }
+                _ => Err(Stop::StepLimit),
+            },
+        );
+        semantics.alu(0x7ff, AluOp::Second);
+        let (emulator, stop) = run(
+            semantics,
+            "unk.r 0x3e, r1, r0, r0, 0x7\nalu.r 0x7ff, r2, r0, r1\nalu.r 0x1, r3, r0, r1\n",
+        );
+        assert_eq!(emulator.registers[1..3], [7, 7]);
+        assert!(matches!(stop, Stop::Unknown { address: 8, .. }));
+    }
+}
@@ -0,0 +1,199 @@
+//! Recording what each executed instruction did.
+
+use std::fmt;
+
+use serde::Serialize;
+
+use super::{csr::CsrAccess, Emulator, Stop};
+
+/// A register read by an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct RegRead {
+    pub reg: u32,
+    pub value: u64,
+}
+
+/// A register written by an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct RegWrite {
+    pub reg: u32,
+    pub old: u64,
+    pub new: u64,
+}
+
+/// A load or store of `len` bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct MemAccess {
+    pub write: bool,
+    pub address: u64,
+    pub len: usize,
+    pub value: u64,
+}
+
+impl fmt::Display for MemAccess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let arrow = if self.write { "<-" } else { "->" };
+        write!(
+            f,
+            "[{:#x}]{} {} {:#x}",
+            self.address, self.len, arrow, self.value
+        )
+    }
+}
+
+/// One executed instruction.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct TraceEntry {
+    /// The number of instructions executed before this one.
+    pub step: u64,
+    pub address: u32,
+    pub word: u32,
+    pub disassembly: String,
+    pub reads: Vec<RegRead>,
+    pub writes: Vec<RegWrite>,
+    pub memory: Vec<MemAccess>,
+    pub csrs: Vec<CsrAccess>,
+}
+
+impl TraceEntry {
+    pub(crate) fn read(&mut self, reg: u32, value: u64) {
+        if reg != 0 && !self.reads.iter().any(|read| read.reg == reg) {
+            self.reads.push(RegRead { reg, value });
+        }
+    }
+}
+
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut accesses = String::new();
+        for read in self.reads.iter() {
+            accesses += &format!(" r{}={:#x}", read.reg, read.value);
+        }
+        for write in self.writes.iter() {
+            accesses += &format!(" r{}: {:#x} -> {:#x}", write.reg, write.old, write.new);
+        }
+        for access in self.memory.iter() {
+            accesses += &format!(" {}", access);
+        }
+        for access in self.csrs.iter() {
+            let arrow = if access.write { "<-" } else { "->" };
+            accesses += &format!(" csr[{:#x}] {} {:#x}", access.csr, arrow, access.value);
+        }
+        let line = format!(
+            "{:>6} {:08x}: {:08x}  {:<28}{}",
+            self.step, self.address, self.word, self.disassembly, accesses
+        );
+        f.write_str(line.trim_end())
+    }
+}
+
+/// Which instructions to record.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TraceFilter {
+    /// Address ranges `start..end` to record, everything if empty.
+    pub ranges: Vec<(u32, u64)>,
+    /// Stop recording after this many entries.
+    pub limit: Option<usize>,
+}
+
+impl TraceFilter {
+    pub fn contains(&self, address: u32) -> bool {
+        self.ranges.is_empty()
+            || self
+                .ranges
+                .iter()
+                .any(|(start, end)| *start <= address && (address as u64) < *end)
+    }
+}
+
+impl Emulator {
+    /// Executes one instruction, also returning what it did.
+    pub fn step_traced(&mut self) -> (Result<(), Stop>, TraceEntry) {
+        let (word, instruction) = self.fetch();
+        *self.recording.borrow_mut() = Some(TraceEntry {
+            step: self.steps,
+            address: self.pc,
+            word,
+            disassembly: instruction.to_string(),
+            ..Default::default()
+        });
+        let logged = self.csrs.log.len();
+        let result = self.step();
+        let mut entry = self.recording.borrow_mut().take().unwrap_or_default();
+        entry.csrs = self.csrs.log[logged..].to_vec();
+        (result, entry)
+    }
+
+    /// Like `run`, passing the entries `filter` selects to `sink`.
+    pub fn run_traced(
+        &mut self,
+        max_steps: u64,
+        filter: &TraceFilter,
+        mut sink: impl FnMut(&TraceEntry),
+    ) -> Stop {
+        let mut recorded = 0;
+        for _ in 0..max_steps {
+            if self.pc == super::RETURN_ADDRESS {
+                return Stop::Returned;
+            }
+            let record = filter.contains(self.pc) && filter.limit.is_none_or(|n| recorded < n);
+            let result = match record {
+                true => {
+                    let (result, entry) = self.step_traced();
+                    if result.is_ok() {
+                        sink(&entry);
+                        recorded += 1;
+                    }
+                    result
+                }
+                false => self.step(),
+            };
+            if let Err(stop) = result {
+                return stop;
+            }
+        }
+        Stop::StepLimit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emu::tests::loaded;
+
+    #[test]
+    fn trace_entries() {
+        let mut emulator = loaded(
+            0x1000,
+            "addi r1, r0, 0x20\nst.d r0, r1, r1, 0x4\nld.d r2, r1, 0x4\nret.d\n",
+        );
+        let mut entries = vec![];
+        let filter = TraceFilter {
+            ranges: vec![(0x1004, 0x100c)],
+            limit: None,
+        };
+        let stop = emulator.run_traced(100, &filter, |entry| entries.push(entry.clone()));
+        assert_eq!(stop, Stop::Returned);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            entries[0].memory,
+            [MemAccess {
+                write: true,
+                address: 0x24,
+                len: 4,
+                value: 0x20
+            }]
+        );
+        assert_eq!(
+            entries[1].to_string(),
+            format!(
+                "{:>6} 00001008: {:08x}  {:<28} r1=0x20 r2: 0x0 -> 0x20 [0x24]4 -> 0x20",
+                2, entries[1].word, "ld.d r2, r1, 0x4"
+            )
+        );
+        assert_eq!(
+            serde_json::to_value(&entries[1]).unwrap()["writes"],
+            serde_json::json!([{"reg": 2, "old": 0, "new": 0x20}])
+        );
+    }
+}
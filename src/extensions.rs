@@ -0,0 +1,470 @@
+use std::{fmt, path::Path, str::FromStr, sync::Arc};
+
+use anyhow::{bail, ensure, Context};
+use serde::Deserialize;
+
+use crate::{
+    fields::{
+        BitField, Bits, Field, FieldBits, Funct, Off14, Off9, Opcode, Operand, Rd, Rs, Rt, Simm,
+        StoreOff14, StoreOff16, Uimm,
+    },
+    instructions::{Assembler, Instruction, INSTRUCTIONS},
+    object::RelocKind,
+    utils::parse_number,
+};
+
+/// The field types an extension instruction can be built from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldKind {
+    Opcode,
+    Funct,
+    Rd,
+    Rs,
+    Rt,
+    Off9,
+    Off14,
+    StoreOff14,
+    StoreOff16,
+    Uimm(u32),
+    Simm(u32),
+    Rel(u32),
+}
+
+impl FromStr for FieldKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let sized = |prefix: &str, max: u32| -> anyhow::Result<Option<u32>> {
+            let Some(bits) = s
+                .strip_prefix(prefix)
+                .and_then(|rest| rest.strip_prefix('<'))
+                .and_then(|rest| rest.strip_suffix('>'))
+            else {
+                return Ok(None);
+            };
+            let bits: u32 = bits.trim().parse()?;
+            ensure!(
+                (1..=max).contains(&bits),
+                "{} must be 1 to {} bits wide",
+                s,
+                max
+            );
+            Ok(Some(bits))
+        };
+
+        Ok(match s {
+            "Opcode" => FieldKind::Opcode,
+            "Funct" => FieldKind::Funct,
+            "Rd" => FieldKind::Rd,
+            "Rs" => FieldKind::Rs,
+            "Rt" => FieldKind::Rt,
+            "Off9" => FieldKind::Off9,
+            "Off14" => FieldKind::Off14,
+            "StoreOff14" => FieldKind::StoreOff14,
+            "StoreOff16" => FieldKind::StoreOff16,
+            _ => {
+                if let Some(bits) = sized("Uimm", 32)? {
+                    FieldKind::Uimm(bits)
+                } else if let Some(bits) = sized("Simm", 32)? {
+                    FieldKind::Simm(bits)
+                } else if let Some(bits) = sized("Rel", 30)? {
+                    FieldKind::Rel(bits)
+                } else {
+                    bail!("Unknown field type: {}", s)
+                }
+            }
+        })
+    }
+}
+
+impl FieldKind {
+    /// Where the field lives in the instruction word.
+    pub fn field(&self) -> BitField {
+        match *self {
+            FieldKind::Opcode => Opcode::FIELD,
+            FieldKind::Funct => Funct::FIELD,
+            FieldKind::Rd => Rd::FIELD,
+            FieldKind::Rs => Rs::FIELD,
+            FieldKind::Rt => Rt::FIELD,
+            FieldKind::Off9 => Off9::FIELD,
+            FieldKind::Off14 => Off14::FIELD,
+            FieldKind::StoreOff14 => StoreOff14::FIELD,
+            FieldKind::StoreOff16 => StoreOff16::FIELD,
+            FieldKind::Uimm(bits) => BitField::new(0, bits),
+            FieldKind::Simm(bits) => BitField::new(0, bits).signed(),
+            FieldKind::Rel(bits) => BitField::new(0, bits).signed().scaled(2),
+        }
+    }
+
+    /// Encodes an operand given as assembly text.
+    pub fn encode<Asm: Assembler>(&self, text: &str, asm: &Asm) -> Result<FieldBits, Asm::Err> {
+        fn typed<T, Asm>(text: &str, asm: &Asm) -> Result<FieldBits, Asm::Err>
+        where
+            T: Operand,
+            T::Err: Into<anyhow::Error>,
+            Asm: Assembler,
+        {
+            let operand = text.parse::<T>().map_err(Into::into)?;
+            operand.encode(asm)
+        }
+
+        let value = match *self {
+            FieldKind::Opcode => return typed::<Opcode, _>(text, asm),
+            FieldKind::Funct => return typed::<Funct, _>(text, asm),
+            FieldKind::Rd => return typed::<Rd, _>(text, asm),
+            FieldKind::Rs => return typed::<Rs, _>(text, asm),
+            FieldKind::Rt => return typed::<Rt, _>(text, asm),
+            FieldKind::Off9 => return typed::<Off9, _>(text, asm),
+            FieldKind::Off14 => return typed::<Off14, _>(text, asm),
+            FieldKind::StoreOff14 => return typed::<StoreOff14, _>(text, asm),
+            FieldKind::StoreOff16 => return typed::<StoreOff16, _>(text, asm),
+            FieldKind::Uimm(_) | FieldKind::Simm(_) => parse_number(text)? as i64,
+            FieldKind::Rel(bits) => {
+                let target = asm.reference(text, RelocKind::Rel(bits))?;
+                target.wrapping_sub(asm.current_address()) as i32 as i64
+            }
+        };
+        let field = self.field();
+        field
+            .check(value)
+            .map_err(|err| anyhow::anyhow!("{}: {}", err, text))?;
+        Ok(FieldBits {
+            bits: field.encode(value),
+            mask: field.mask(),
+        })
+    }
+
+    /// Decodes the operand from an instruction word into assembly text.
+    pub fn decode(&self, word: u32, address: u32) -> String {
+        match *self {
+            FieldKind::Opcode => Opcode::decode(word, address).to_string(),
+            FieldKind::Funct => Funct::decode(word, address).to_string(),
+            FieldKind::Rd => Rd::decode(word, address).to_string(),
+            FieldKind::Rs => Rs::decode(word, address).to_string(),
+            FieldKind::Rt => Rt::decode(word, address).to_string(),
+            FieldKind::Off9 => Off9::decode(word, address).to_string(),
+            FieldKind::Off14 => Off14::decode(word, address).to_string(),
+            FieldKind::StoreOff14 => StoreOff14::decode(word, address).to_string(),
+            FieldKind::StoreOff16 => StoreOff16::decode(word, address).to_string(),
+            FieldKind::Uimm(_) => Uimm::<32>(self.field().decode(word) as u64).to_string(),
+            FieldKind::Simm(_) => Simm::<32>(self.field().decode(word)).to_string(),
+            FieldKind::Rel(_) => {
+                let offset = self.field().decode(word);
+                format!("{:#x}", address.wrapping_add(offset as u32))
+            }
+        }
+    }
+}
+
+/// One field of an extension instruction, either an operand or fixed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtensionField {
+    pub kind: FieldKind,
+    pub fixed: Option<String>,
+}
+
+impl FromStr for ExtensionField {
+    type Err = anyhow::Error;
+
+    /// Parses `Type` for an operand or `Type = value` for a fixed field.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, fixed) = match s.split_once('=') {
+            Some((kind, fixed)) => (kind, Some(fixed.trim().to_string())),
+            None => (s, None),
+        };
+        Ok(Self {
+            kind: kind.trim().parse()?,
+            fixed,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+struct ExtensionDefToml {
+    mnemonic: String,
+    #[serde(default)]
+    doc: String,
+    fields: Vec<String>,
+}
+
+/// An instruction defined at runtime in terms of existing field types.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtensionDef {
+    pub mnemonic: String,
+    pub doc: String,
+    pub fields: Vec<ExtensionField>,
+}
+
+impl ExtensionDef {
+    fn operands(&self) -> impl Iterator<Item = &ExtensionField> {
+        self.fields.iter().filter(|field| field.fixed.is_none())
+    }
+}
+
+/// An instance of an extension instruction with its operands as assembly text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtInstruction {
+    pub def: Arc<ExtensionDef>,
+    pub operands: Vec<String>,
+}
+
+impl ExtInstruction {
+    pub fn assemble<Asm: Assembler>(&self, asm: &mut Asm) -> Result<(), Asm::Err> {
+        if self.operands.len() != self.def.operands().count() {
+            return Err(
+                anyhow::anyhow!("Wrong number of parameters for {}", self.def.mnemonic).into(),
+            );
+        }
+        let mut operands = self.operands.iter();
+        let mut word = FieldBits::EMPTY;
+        for field in self.def.fields.iter() {
+            let text = match &field.fixed {
+                Some(fixed) => fixed,
+                None => operands.next().expect("operand count checked above"),
+            };
+            let bits = field.kind.encode(text, &*asm)?;
+            word = FieldBits {
+                bits: word.bits | bits.bits,
+                mask: word.mask | bits.mask,
+            };
+        }
+        asm.emit(word)
+    }
+}
+
+impl fmt::Display for ExtInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.def.mnemonic)?;
+        for (index, operand) in self.operands.iter().enumerate() {
+            let separator = if index == 0 { " " } else { ", " };
+            write!(f, "{}{}", separator, operand)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ExtensionsToml {
+    #[serde(default, rename = "instruction")]
+    instructions: Vec<ExtensionDefToml>,
+}
+
+/// Instruction definitions loaded at runtime, e.g. from `--isa-ext` files.
+///
+/// ```toml
+/// [[instruction]]
+/// mnemonic = "mul"
+/// doc = "Multiply rs and rt."
+/// fields = ["Opcode = 0x3f", "Rd", "Rs", "Rt", "Funct = 0x00c"]
+/// ```
+///
+/// Fields are listed without their bit positions; operands are taken in the
+/// order of the fields that have no fixed value.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Extensions {
+    defs: Vec<Arc<ExtensionDef>>,
+}
+
+impl Extensions {
+    pub fn from_toml(source: &str) -> anyhow::Result<Self> {
+        let mut extensions = Self::default();
+        let file: ExtensionsToml = toml::from_str(source)?;
+        for def in file.instructions {
+            let fields = def
+                .fields
+                .iter()
+                .map(|field| field.parse())
+                .collect::<anyhow::Result<Vec<ExtensionField>>>()
+                .with_context(|| format!("Bad definition of {}", def.mnemonic))?;
+            extensions.define(ExtensionDef {
+                mnemonic: def.mnemonic,
+                doc: def.doc,
+                fields,
+            })?;
+        }
+        Ok(extensions)
+    }
+
+    pub fn load(&mut self, path: &Path) -> anyhow::Result<()> {
+        let source = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let extensions = Self::from_toml(&source)
+            .with_context(|| format!("Bad ISA extension {}", path.display()))?;
+        for def in extensions.defs {
+            self.define(Arc::unwrap_or_clone(def))?;
+        }
+        Ok(())
+    }
+
+    pub fn define(&mut self, def: ExtensionDef) -> anyhow::Result<()> {
+        ensure!(
+            INSTRUCTIONS
+                .iter()
+                .all(|spec| spec.mnemonic != def.mnemonic)
+                && self.get(&def.mnemonic).is_none(),
+            "Instruction already defined: {}",
+            def.mnemonic
+        );
+        let mut mask = 0;
+        for field in def.fields.iter() {
+            let field_mask = field.kind.field().mask();
+            ensure!(
+                mask & field_mask == 0,
+                "Overlapping fields in {}: {:#010x} and {:#010x}",
+                def.mnemonic,
+                mask,
+                field_mask
+            );
+            mask |= field_mask;
+
+            if let Some(fixed) = &field.fixed {
+                field
+                    .kind
+                    .encode(fixed, &NoLabels)
+                    .with_context(|| format!("Bad fixed value in {}", def.mnemonic))?;
+            }
+        }
+        self.defs.push(Arc::new(def));
+        Ok(())
+    }
+
+    pub fn get(&self, mnemonic: &str) -> Option<&Arc<ExtensionDef>> {
+        self.defs.iter().find(|def| def.mnemonic == mnemonic)
+    }
+
+    pub fn defs(&self) -> impl Iterator<Item = &ExtensionDef> {
+        self.defs.iter().map(|def| def.as_ref())
+    }
+
+    /// Parses a line if its mnemonic is defined by an extension.
+    pub fn parse(&self, line: &str) -> Option<anyhow::Result<Instruction>> {
+        let line = line.trim();
+        let (cmd, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let def = self.get(cmd)?;
+        Some((|| {
+            let params = rest
+                .split(',')
+                .map(|p| p.trim())
+                .filter(|p| !p.is_empty())
+                .collect::<Vec<_>>();
+            ensure!(
+                params.len() == def.operands().count(),
+                "Wrong number of parameters"
+            );
+            for (field, param) in def.operands().zip(params.iter()) {
+                if !matches!(field.kind, FieldKind::Rel(_)) {
+                    field.kind.encode(param, &NoLabels)?;
+                }
+            }
+            Ok(Instruction::Ext(ExtInstruction {
+                def: def.clone(),
+                operands: params.into_iter().map(String::from).collect(),
+            }))
+        })())
+    }
+
+    /// Decodes a word as the first extension instruction that encodes to it.
+    pub fn decode(&self, word: u32, address: u32) -> Option<Instruction> {
+        self.defs.iter().find_map(|def| {
+            let instruction = Instruction::Ext(ExtInstruction {
+                def: def.clone(),
+                operands: def
+                    .operands()
+                    .map(|field| field.kind.decode(word, address))
+                    .collect(),
+            });
+            instruction.encodes_to(word, address).then_some(instruction)
+        })
+    }
+}
+
+/// Validates operands outside of an assembly pass.
+struct NoLabels;
+
+impl Assembler for NoLabels {
+    type Err = anyhow::Error;
+
+    fn current_address(&self) -> u32 {
+        0
+    }
+
+    fn label(&mut self, _name: &str, _address: u32) -> Result<(), Self::Err> {
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str) -> Result<u32, Self::Err> {
+        bail!("label undefined: {}", name)
+    }
+
+    fn emit(&mut self, _bits: impl Bits) -> Result<(), Self::Err> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MUL: &str = r#"
+        [[instruction]]
+        mnemonic = "mul"
+        doc = "Multiply rs and rt."
+        fields = ["Opcode = 0x3f", "Rd", "Rs", "Rt", "Funct = 0x00c"]
+
+        [[instruction]]
+        mnemonic = "b.x"
+        fields = ["Opcode = 0x2c", "Rs", "Rel<16>"]
+    "#;
+
+    #[test]
+    fn extension_parse_and_assemble() {
+        let extensions = Extensions::from_toml(MUL).unwrap();
+        let (code, _) =
+            crate::assemble_with(&extensions, 0x100, "lbl top\nmul r7, r5, r6\nb.x r5, top\n")
+                .unwrap();
+        assert_eq!(code, vec![0xfc, 0xa7, 0x30, 0x0c, 0xb0, 0xa0, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn extension_decode() {
+        let extensions = Extensions::from_toml(MUL).unwrap();
+        let decoded = Instruction::decode_with(0xfca7300c, 0, &extensions);
+        assert_eq!(decoded.to_string(), "mul r7, r5, r6");
+        let decoded = Instruction::decode_with(0xb0a0ffff, 0x104, &extensions);
+        assert_eq!(decoded.to_string(), "b.x r5, 0x100");
+        let decoded = Instruction::decode_with(0xfca7300d, 0, &extensions);
+        assert_eq!(decoded.mnemonic(), "alu.r");
+    }
+
+    #[test]
+    fn extension_errors() {
+        assert!(Extensions::from_toml(
+            "[[instruction]]\nmnemonic = \"addi\"\nfields = [\"Opcode = 0x01\"]\n"
+        )
+        .is_err());
+        assert!(Extensions::from_toml(
+            "[[instruction]]\nmnemonic = \"foo\"\nfields = [\"Opcode = 0x40\"]\n"
+        )
+        .is_err());
+        assert!(Extensions::from_toml(
+            "[[instruction]]\nmnemonic = \"foo\"\nfields = [\"Simm<33>\"]\n"
+        )
+        .is_err());
+        assert!(Extensions::from_toml(
+            "[[instruction]]\nmnemonic = \"foo\"\nfields = [\"Rt\", \"Uimm<16>\"]\n"
+        )
+        .is_err());
+
+        let extensions = Extensions::from_toml(MUL).unwrap();
+        assert!(extensions.parse("mul r7, r5").unwrap().is_err());
+        assert!(extensions.parse("mul r7, r5, r32").unwrap().is_err());
+        assert!(extensions.parse("addi r7, r5, 1").is_none());
+
+        let mul = ExtInstruction {
+            def: extensions.get("mul").unwrap().clone(),
+            operands: vec!["r7".to_string()],
+        };
+        let mut asm = crate::assembler::OutputAssembler::new(0, Default::default());
+        assert!(mul.assemble(&mut asm).is_err());
+    }
+}
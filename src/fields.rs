@@ -1,33 +1,236 @@
-use std::{convert::Infallible, str::FromStr};
+use std::{convert::Infallible, fmt, str::FromStr};
 use thiserror::Error;
 
+use crate::{instructions::Assembler, object::RelocKind};
+
 pub trait Bits {
     fn bits(&self) -> u32;
+
+    /// The bits of the instruction word this value is responsible for.
+    fn mask(&self) -> u32;
 }
 
-macro_rules! impl_bits_at_offset_inner {
-    ($structname:ty, $offset:expr) => {
-        impl Bits for $structname {
-            fn bits(&self) -> u32 {
-                self.0.bits() << $offset
-            }
+/// Bits that have already been placed in an instruction word.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FieldBits {
+    pub bits: u32,
+    pub mask: u32,
+}
+
+impl FieldBits {
+    pub const EMPTY: Self = Self { bits: 0, mask: 0 };
+
+    pub fn of(bits: &impl Bits) -> Self {
+        Self {
+            bits: bits.bits(),
+            mask: bits.mask(),
         }
-    };
+    }
+}
+
+impl Bits for FieldBits {
+    fn bits(&self) -> u32 {
+        self.bits
+    }
+
+    fn mask(&self) -> u32 {
+        self.mask
+    }
+}
+
+/// Where a value lives in an instruction word.
+///
+/// A field is made of up to two slices of `(bit offset, width)`, filled with
+/// the value's bits least significant slice first. The value is shifted right
+/// by `shift` before encoding, so it must be aligned to `1 << shift`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BitField {
+    slices: [(u32, u32); 2],
+    signed: bool,
+    shift: u32,
+}
+
+impl BitField {
+    pub const fn new(offset: u32, width: u32) -> Self {
+        Self {
+            slices: [(offset, width), (0, 0)],
+            signed: false,
+            shift: 0,
+        }
+    }
+
+    /// Continues the field with a slice holding its next more significant bits.
+    pub const fn then(self, offset: u32, width: u32) -> Self {
+        Self {
+            slices: [self.slices[0], (offset, width)],
+            ..self
+        }
+    }
+
+    pub const fn signed(self) -> Self {
+        Self {
+            signed: true,
+            ..self
+        }
+    }
+
+    pub const fn scaled(self, shift: u32) -> Self {
+        Self { shift, ..self }
+    }
+
+    pub fn slices(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.slices.iter().copied().filter(|(_, width)| *width != 0)
+    }
+
+    pub fn width(&self) -> u32 {
+        self.slices().map(|(_, width)| width).sum()
+    }
+
+    pub fn mask(&self) -> u32 {
+        self.slices()
+            .map(|(offset, width)| (Self::low_mask(width) << offset) as u32)
+            .fold(0, |mask, slice| mask | slice)
+    }
+
+    fn low_mask(width: u32) -> u64 {
+        u64::MAX.checked_shr(64 - width).unwrap_or(0)
+    }
+
+    /// Checks that `value` is aligned and fits the field.
+    pub fn check(&self, value: i64) -> Result<(), ParseImmidiateError> {
+        if value & (Self::low_mask(self.shift) as i64) != 0 {
+            return Err(ParseImmidiateError::Unaligned);
+        }
+        let value = value >> self.shift;
+        let width = self.width();
+        let (min, max) = if self.signed {
+            (-(1i128 << (width - 1)), (1i128 << (width - 1)) - 1)
+        } else {
+            (0, (1i128 << width) - 1)
+        };
+        if (value as i128) < min || (value as i128) > max {
+            return Err(ParseImmidiateError::OutOfRange);
+        }
+        Ok(())
+    }
+
+    /// Places `value` in the field, dropping any bits that do not fit.
+    pub fn encode(&self, value: i64) -> u32 {
+        let mut value = (value >> self.shift) as u64;
+        let mut word = 0;
+        for (offset, width) in self.slices() {
+            word |= ((value & Self::low_mask(width)) << offset) as u32;
+            value = value.checked_shr(width).unwrap_or(0);
+        }
+        word
+    }
+
+    /// Extracts the field's value from an instruction word.
+    pub fn decode(&self, word: u32) -> i64 {
+        let mut value = 0u64;
+        let mut position = 0;
+        for (offset, width) in self.slices() {
+            value |= ((word >> offset) as u64 & Self::low_mask(width)) << position;
+            position += width;
+        }
+        let value = if self.signed && position < 64 {
+            ((value << (64 - position)) as i64) >> (64 - position)
+        } else {
+            value as i64
+        };
+        value << self.shift
+    }
+}
+
+/// A value stored in a `BitField` of the instruction word.
+pub trait Field: Sized {
+    const FIELD: BitField;
+
+    /// The value as written in assembly, before scaling.
+    fn value(&self) -> i64;
+
+    fn from_value(value: i64) -> Self;
+}
+
+impl<T: Field> Bits for T {
+    fn bits(&self) -> u32 {
+        T::FIELD.encode(self.value())
+    }
+
+    fn mask(&self) -> u32 {
+        T::FIELD.mask()
+    }
+}
+
+/// Parses an unsigned number that has to fit `T`'s field.
+fn parse_field<T: Field>(s: &str) -> Result<T, ParseImmidiateError> {
+    let number: Uimm<64> = s.parse()?;
+    let value = i64::try_from(number.0).map_err(|_| ParseImmidiateError::OutOfRange)?;
+    T::FIELD.check(value)?;
+    Ok(T::from_value(value))
+}
+
+/// An instruction operand as it appears in an instruction table row.
+///
+/// Operands are parsed from and displayed as assembly text, encoded into their
+/// bits of an instruction word and decoded back out of one.
+pub trait Operand: FromStr + fmt::Display + Sized {
+    fn encode<Asm: Assembler>(&self, asm: &Asm) -> Result<FieldBits, Asm::Err>;
+
+    fn decode(word: u32, address: u32) -> Self;
+
+    /// The bits of the instruction word the operand is encoded into.
+    fn mask() -> u32;
+}
+
+impl<T: Field + FromStr + fmt::Display> Operand for T {
+    fn encode<Asm: Assembler>(&self, _asm: &Asm) -> Result<FieldBits, Asm::Err> {
+        Ok(FieldBits::of(self))
+    }
+
+    fn decode(word: u32, _address: u32) -> Self {
+        T::from_value(T::FIELD.decode(word))
+    }
+
+    fn mask() -> u32 {
+        T::FIELD.mask()
+    }
 }
 
 pub struct Or<A: Bits, B: Bits>(A, B);
 
+impl<A: Bits, B: Bits> Or<A, B> {
+    /// Combines two values, which must not overlap.
+    ///
+    /// Overlap is a bug in an encoding rather than in its operands, so it is
+    /// only checked in debug builds.
+    pub fn new(a: A, b: B) -> Self {
+        debug_assert_eq!(
+            a.mask() & b.mask(),
+            0,
+            "overlapping fields: {:#010x} and {:#010x}",
+            a.mask(),
+            b.mask()
+        );
+        Self(a, b)
+    }
+}
+
 impl<A: Bits, B: Bits> Bits for Or<A, B> {
     fn bits(&self) -> u32 {
         self.0.bits() | self.1.bits()
     }
+
+    fn mask(&self) -> u32 {
+        self.0.mask() | self.1.mask()
+    }
 }
 
 impl<Rhs: Bits> core::ops::BitOr<Rhs> for Opcode {
     type Output = Or<Opcode, Rhs>;
 
     fn bitor(self, rhs: Rhs) -> Self::Output {
-        Or(self, rhs)
+        Or::new(self, rhs)
     }
 }
 
@@ -35,7 +238,7 @@ impl<A: Bits, B: Bits, Rhs: Bits> core::ops::BitOr<Rhs> for Or<A, B> {
     type Output = Or<Or<A, B>, Rhs>;
 
     fn bitor(self, rhs: Rhs) -> Self::Output {
-        Or(self, rhs)
+        Or::new(self, rhs)
     }
 }
 
@@ -46,6 +249,9 @@ pub enum ParseImmidiateError {
 
     #[error("Immidiate out of range")]
     OutOfRange,
+
+    #[error("Unaligned immidiate")]
+    Unaligned,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd)]
@@ -56,9 +262,7 @@ impl<const BITS: usize> Uimm<BITS> {
         if BITS == 64 {
             return Ok(Self(number));
         }
-        if number >= (1 << BITS) {
-            return Err(ParseImmidiateError::OutOfRange);
-        }
+        Self::FIELD.check(number as i64)?;
 
         Ok(Self(number))
     }
@@ -79,23 +283,30 @@ impl<const BITS: usize> FromStr for Uimm<BITS> {
     }
 }
 
-impl<const BITS: usize> Bits for Uimm<BITS> {
-    fn bits(&self) -> u32 {
-        (self.0 & ((1 << BITS) - 1)) as u32
+impl<const BITS: usize> Field for Uimm<BITS> {
+    const FIELD: BitField = BitField::new(0, BITS as u32);
+
+    fn value(&self) -> i64 {
+        self.0 as i64
+    }
+
+    fn from_value(value: i64) -> Self {
+        Self(value as u64)
     }
 }
+
+impl<const BITS: usize> fmt::Display for Uimm<BITS> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}", self.0)
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd)]
 pub struct Simm<const BITS: usize>(pub i64);
 
 impl<const BITS: usize> Simm<BITS> {
     pub fn new(number: i64) -> Result<Self, ParseImmidiateError> {
-        if number < -(1 << (BITS - 1)) {
-            return Err(ParseImmidiateError::OutOfRange);
-        }
-
-        if number >= (1 << (BITS - 1)) {
-            return Err(ParseImmidiateError::OutOfRange);
-        }
+        Self::FIELD.check(number)?;
 
         Ok(Self(number))
     }
@@ -124,9 +335,25 @@ impl<const BITS: usize> FromStr for Simm<BITS> {
     }
 }
 
-impl<const BITS: usize> Bits for Simm<BITS> {
-    fn bits(&self) -> u32 {
-        self.0 as u32 & ((1 << BITS) - 1)
+impl<const BITS: usize> Field for Simm<BITS> {
+    const FIELD: BitField = BitField::new(0, BITS as u32).signed();
+
+    fn value(&self) -> i64 {
+        self.0
+    }
+
+    fn from_value(value: i64) -> Self {
+        Self(value)
+    }
+}
+
+impl<const BITS: usize> fmt::Display for Simm<BITS> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0 < 0 {
+            write!(f, "-{:#x}", self.0.unsigned_abs())
+        } else {
+            write!(f, "{:#x}", self.0)
+        }
     }
 }
 
@@ -151,7 +378,23 @@ impl FromStr for Opcode {
     }
 }
 
-impl_bits_at_offset_inner!(Opcode, 26);
+impl Field for Opcode {
+    const FIELD: BitField = BitField::new(26, 6);
+
+    fn value(&self) -> i64 {
+        self.0 .0 as i64
+    }
+
+    fn from_value(value: i64) -> Self {
+        Self(Uimm(value as u64))
+    }
+}
+
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#04x}", self.0 .0)
+    }
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd)]
 pub struct Funct(pub Uimm<11>);
@@ -174,76 +417,132 @@ impl FromStr for Funct {
     }
 }
 
-impl_bits_at_offset_inner!(Funct, 0);
+impl Field for Funct {
+    const FIELD: BitField = BitField::new(0, 11);
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd)]
-pub struct Off9(pub Uimm<9>);
+    fn value(&self) -> i64 {
+        self.0 .0 as i64
+    }
 
-impl FromStr for Off9 {
-    type Err = anyhow::Error;
+    fn from_value(value: i64) -> Self {
+        Self(Uimm(value as u64))
+    }
+}
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let uimm11: Uimm<11> = s.parse()?;
-        anyhow::ensure!(uimm11.0 & 0x3 == 0, "unaligned offset");
-        Ok(Self(Uimm(uimm11.0 >> 2)))
+impl fmt::Display for Funct {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#05x}", self.0 .0)
     }
 }
 
-impl_bits_at_offset_inner!(Off9, 2);
+/// Implements a 5-bit selector field at bits 20:16.
+macro_rules! impl_selector {
+    ($structname:ident) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd)]
+        pub struct $structname(pub Uimm<5>);
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd)]
-pub struct Off14(pub Uimm<14>);
+        impl FromStr for $structname {
+            type Err = ParseImmidiateError;
 
-impl FromStr for Off14 {
-    type Err = anyhow::Error;
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                Ok(Self(s.parse()?))
+            }
+        }
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let uimm16: Uimm<16> = s.parse()?;
-        anyhow::ensure!(uimm16.0 & 0x3 == 0, "unaligned offset");
-        Ok(Self(Uimm(uimm16.0 >> 2)))
-    }
+        impl Field for $structname {
+            const FIELD: BitField = BitField::new(16, 5);
+
+            fn value(&self) -> i64 {
+                self.0 .0 as i64
+            }
+
+            fn from_value(value: i64) -> Self {
+                Self(Uimm(value as u64))
+            }
+        }
+
+        impl fmt::Display for $structname {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{:#x}", self.0 .0)
+            }
+        }
+    };
 }
 
-impl_bits_at_offset_inner!(Off14, 2);
+impl_selector!(Cmpop);
+impl_selector!(Bitsel);
+
+/// Implements a byte offset field whose inner value counts words.
+macro_rules! impl_word_offset {
+    ($structname:ty, $field:expr) => {
+        impl FromStr for $structname {
+            type Err = ParseImmidiateError;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                parse_field(s)
+            }
+        }
+
+        impl Field for $structname {
+            const FIELD: BitField = $field;
+
+            fn value(&self) -> i64 {
+                (self.0 .0 << 2) as i64
+            }
+
+            fn from_value(value: i64) -> Self {
+                Self(Uimm(value as u64 >> 2))
+            }
+        }
+
+        impl fmt::Display for $structname {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{:#x}", self.value())
+            }
+        }
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd)]
+pub struct Off9(pub Uimm<9>);
+impl_word_offset!(Off9, BitField::new(2, 9).scaled(2));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd)]
+pub struct Off14(pub Uimm<14>);
+impl_word_offset!(Off14, BitField::new(2, 14).scaled(2));
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd)]
 pub struct StoreOff16(pub Uimm<16>);
 
 impl FromStr for StoreOff16 {
-    type Err = anyhow::Error;
+    type Err = ParseImmidiateError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(Self(s.parse()?))
+        parse_field(s)
     }
 }
 
-impl Bits for StoreOff16 {
-    fn bits(&self) -> u32 {
-        let inner = self.0.bits();
-        ((0xf800 & inner) << 5) | (inner & 0x7ff)
-    }
-}
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd)]
-pub struct StoreOff14(pub Uimm<14>);
+impl Field for StoreOff16 {
+    const FIELD: BitField = BitField::new(0, 11).then(16, 5);
 
-impl FromStr for StoreOff14 {
-    type Err = anyhow::Error;
+    fn value(&self) -> i64 {
+        self.0 .0 as i64
+    }
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let uimm16: Uimm<16> = s.parse()?;
-        anyhow::ensure!(uimm16.0 & 0x3 == 0, "unaligned offset");
-        Ok(Self(Uimm(uimm16.0 >> 2)))
+    fn from_value(value: i64) -> Self {
+        Self(Uimm(value as u64))
     }
 }
 
-impl Bits for StoreOff14 {
-    fn bits(&self) -> u32 {
-        let inner = self.0.bits() << 2;
-        ((0xf800 & inner) << 5) | (inner & 0x7fc)
+impl fmt::Display for StoreOff16 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}", self.0 .0)
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd)]
+pub struct StoreOff14(pub Uimm<14>);
+impl_word_offset!(StoreOff14, BitField::new(2, 9).then(16, 5).scaled(2));
 
 #[derive(Debug, Error, Clone, Copy, PartialEq, Eq, PartialOrd)]
 pub enum ParseRegisterError {
@@ -278,9 +577,9 @@ impl FromStr for Reg {
     }
 }
 
-impl Bits for Reg {
-    fn bits(&self) -> u32 {
-        self.0
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "r{}", self.0)
     }
 }
 
@@ -294,7 +593,23 @@ macro_rules! impl_register {
             }
         }
 
-        impl_bits_at_offset_inner!($structname, $offset);
+        impl Field for $structname {
+            const FIELD: BitField = BitField::new($offset, 5);
+
+            fn value(&self) -> i64 {
+                self.0 .0 as i64
+            }
+
+            fn from_value(value: i64) -> Self {
+                Self(Reg(value as u32))
+            }
+        }
+
+        impl fmt::Display for $structname {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                self.0.fmt(f)
+            }
+        }
     };
 }
 
@@ -316,14 +631,22 @@ pub enum Jmpop {
     Call,
 }
 
+impl Jmpop {
+    pub const FIELD: BitField = BitField::new(24, 2);
+}
+
 impl Bits for Jmpop {
     fn bits(&self) -> u32 {
-        let bits = match *self {
+        let value = match *self {
             Jmpop::Call => 0x0,
             Jmpop::Jump => 0x1,
         };
 
-        bits << 24
+        Self::FIELD.encode(value)
+    }
+
+    fn mask(&self) -> u32 {
+        Self::FIELD.mask()
     }
 }
 
@@ -335,14 +658,24 @@ pub enum Memop {
     LowerWord,
 }
 
+impl Memop {
+    pub const FIELD: BitField = BitField::new(0, 2);
+}
+
 impl Bits for Memop {
     fn bits(&self) -> u32 {
-        match *self {
+        let value = match *self {
             Memop::Qword => 0b00,
             Memop::UpperWord => 0b01,
             Memop::Dword => 0b10,
             Memop::LowerWord => 0b11,
-        }
+        };
+
+        Self::FIELD.encode(value)
+    }
+
+    fn mask(&self) -> u32 {
+        Self::FIELD.mask()
     }
 }
 
@@ -357,6 +690,130 @@ impl FromStr for Label {
     }
 }
 
+impl fmt::Display for Label {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// A `BITS` wide signed word offset from the current address to a label.
+///
+/// A label that is a number is taken as an absolute address, which is also how
+/// decoded targets are represented.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd)]
+pub struct Rel<const BITS: usize>(pub Label);
+
+impl<const BITS: usize> Rel<BITS> {
+    pub const FIELD: BitField = BitField::new(0, BITS as u32).signed().scaled(2);
+
+    pub fn target<Asm: Assembler>(&self, asm: &Asm) -> Result<u32, Asm::Err> {
+        asm.reference(&self.0 .0, RelocKind::Rel(BITS as u32))
+    }
+}
+
+impl<const BITS: usize> FromStr for Rel<BITS> {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.parse()?))
+    }
+}
+
+impl<const BITS: usize> Operand for Rel<BITS> {
+    fn encode<Asm: Assembler>(&self, asm: &Asm) -> Result<FieldBits, Asm::Err> {
+        let offset = self.target(asm)?.wrapping_sub(asm.current_address()) as i32 as i64;
+        Self::FIELD
+            .check(offset)
+            .map_err(|err| anyhow::anyhow!("Bad target {}: {}", self, err))?;
+        Ok(FieldBits {
+            bits: Self::FIELD.encode(offset),
+            mask: Self::FIELD.mask(),
+        })
+    }
+
+    fn decode(word: u32, address: u32) -> Self {
+        let offset = Self::FIELD.decode(word);
+        Self(Label(format!("{:#x}", address.wrapping_add(offset as u32))))
+    }
+
+    fn mask() -> u32 {
+        Self::FIELD.mask()
+    }
+}
+
+impl<const BITS: usize> fmt::Display for Rel<BITS> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// The 16-bit immediate of a `set` instruction.
+///
+/// Besides a number this can be `%hwN(symbol)`, halfword `N` of the symbol's
+/// address in the numbering of `setN`, so `%hw3` is bits 15:0.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd)]
+pub enum SetImm {
+    Imm(Uimm<16>),
+    Hw(u32, Label),
+}
+
+impl SetImm {
+    /// Halfword `hw` of `value`, with `%hw0` being bits 63:48.
+    pub fn halfword(value: u64, hw: u32) -> u64 {
+        (value >> (48 - 16 * hw)) & 0xffff
+    }
+}
+
+impl FromStr for SetImm {
+    type Err = ParseImmidiateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some(rest) = s.strip_prefix("%hw") else {
+            return Ok(Self::Imm(s.parse()?));
+        };
+        let (hw, symbol) = rest
+            .strip_suffix(')')
+            .and_then(|rest| rest.split_once('('))
+            .ok_or(ParseImmidiateError::InvalidNumber)?;
+        match hw.parse() {
+            Ok(hw @ 0..=3) => Ok(Self::Hw(hw, Label(symbol.trim().to_string()))),
+            _ => Err(ParseImmidiateError::OutOfRange),
+        }
+    }
+}
+
+impl Operand for SetImm {
+    fn encode<Asm: Assembler>(&self, asm: &Asm) -> Result<FieldBits, Asm::Err> {
+        match self {
+            Self::Imm(imm) => Ok(FieldBits::of(imm)),
+            Self::Hw(hw, symbol) => {
+                let value = asm.reference(&symbol.0, RelocKind::Hw(*hw))?;
+                Ok(FieldBits::of(&Uimm::<16>(Self::halfword(
+                    value as u64,
+                    *hw,
+                ))))
+            }
+        }
+    }
+
+    fn decode(word: u32, address: u32) -> Self {
+        Self::Imm(Uimm::decode(word, address))
+    }
+
+    fn mask() -> u32 {
+        Uimm::<16>::FIELD.mask()
+    }
+}
+
+impl fmt::Display for SetImm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Imm(imm) => imm.fmt(f),
+            Self::Hw(hw, symbol) => write!(f, "%hw{}({})", hw, symbol),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -484,4 +941,70 @@ mod tests {
         assert_eq!(Jmpop::Call.bits(), 0x00000000);
         assert_eq!(Jmpop::Jump.bits(), 0x01000000);
     }
+
+    #[test]
+    fn bitfield_split() {
+        let field = BitField::new(2, 9).then(16, 5).scaled(2);
+        assert_eq!(field.width(), 14);
+        assert_eq!(field.encode(0x7fc), 0x000007fc);
+        assert_eq!(field.encode(0x800), 0x00010000);
+        assert_eq!(field.encode(0xfffc), 0x001f07fc);
+        assert_eq!(field.decode(0x001f07fc), 0xfffc);
+        assert_eq!(field.check(0xfffc), Ok(()));
+        assert_eq!(field.check(0x10000), Err(ParseImmidiateError::OutOfRange));
+        assert_eq!(field.check(0x6), Err(ParseImmidiateError::Unaligned));
+    }
+
+    #[test]
+    fn bitfield_signed() {
+        let field = BitField::new(0, 24).signed().scaled(2);
+        assert_eq!(field.encode(-4), 0x00ffffff);
+        assert_eq!(field.decode(0x95ffffff), -4);
+        assert_eq!(field.decode(0x00800000), -(1 << 25));
+        assert_eq!(field.check(-(1 << 25)), Ok(()));
+        assert_eq!(
+            field.check(-(1 << 25) - 4),
+            Err(ParseImmidiateError::OutOfRange)
+        );
+        assert_eq!(field.check(1 << 25), Err(ParseImmidiateError::OutOfRange));
+    }
+
+    #[test]
+    fn parse_offsets() {
+        assert_eq!("0x8".parse::<Off9>(), Ok(Off9(Uimm(2))));
+        assert_eq!("0x7fc".parse::<Off9>(), Ok(Off9(Uimm(0x1ff))));
+        assert_eq!(
+            "0x800".parse::<Off9>(),
+            Err(ParseImmidiateError::OutOfRange)
+        );
+        assert_eq!("0x6".parse::<Off14>(), Err(ParseImmidiateError::Unaligned));
+        assert_eq!(StoreOff14(Uimm(0x3fff)).bits(), 0x001f07fc);
+        assert_eq!(StoreOff16(Uimm(0xffff)).bits(), 0x001f07ff);
+        assert_eq!(StoreOff16::decode(0x001f07ff, 0), StoreOff16(Uimm(0xffff)));
+    }
+
+    #[test]
+    #[should_panic(expected = "overlapping fields")]
+    fn or_overlapping() {
+        let _ = Or::new(Rd(Reg(1)), Rd(Reg(2)));
+    }
+
+    #[test]
+    fn parse_set_imm() {
+        assert_eq!("0x12".parse::<SetImm>(), Ok(SetImm::Imm(Uimm(0x12))));
+        assert_eq!(
+            "%hw3(foo)".parse::<SetImm>(),
+            Ok(SetImm::Hw(3, Label("foo".to_string())))
+        );
+        assert_eq!(
+            "%hw4(foo)".parse::<SetImm>(),
+            Err(ParseImmidiateError::OutOfRange)
+        );
+        assert_eq!(
+            SetImm::Hw(2, Label("foo".to_string())).to_string(),
+            "%hw2(foo)"
+        );
+        assert_eq!(SetImm::halfword(0x1234_5678, 2), 0x1234);
+        assert_eq!(SetImm::halfword(0x1234_5678, 3), 0x5678);
+    }
 }
@@ -0,0 +1,274 @@
+//! Finding bytes that must not appear in the assembled code.
+
+use std::{collections::BTreeMap, fmt};
+
+use crate::{
+    assembler::OutputAssembler,
+    extensions::Extensions,
+    fields::{Rd, Reg, Rs, Rt, SetImm, Simm, Uimm},
+    instructions::{Assembler, Instruction},
+    listing::Listing,
+};
+
+/// Forbidden bytes produced by one source line, or found in the padding between regions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    /// The line number and text, `None` for padding.
+    pub line: Option<(usize, String)>,
+    /// The address and value of each forbidden byte.
+    pub bytes: Vec<(u32, u8)>,
+    /// Replacements for the line that avoid the forbidden bytes.
+    pub alternatives: Vec<String>,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes = self
+            .bytes
+            .iter()
+            .map(|(address, byte)| format!("{:#04x} at {:#x}", byte, address))
+            .collect::<Vec<_>>()
+            .join(", ");
+        match &self.line {
+            Some((line, text)) => write!(f, "line {}: `{}` contains {}", line, text.trim(), bytes)?,
+            None => write!(f, "padding contains {}", bytes)?,
+        }
+        for alternative in self.alternatives.iter() {
+            write!(f, "\n    try {}", alternative)?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks `code`, assembled from `listing` at `base_addr`, for `forbidden` bytes.
+pub fn check(
+    extensions: &Extensions,
+    listing: &Listing,
+    base_addr: u32,
+    code: &[u8],
+    forbidden: &[u8],
+) -> Vec<Violation> {
+    let mut violations: Vec<Violation> = vec![];
+    for (offset, byte) in code.iter().enumerate() {
+        if !forbidden.contains(byte) {
+            continue;
+        }
+        let address = base_addr + offset as u32;
+        let line = listing.lines.iter().find(|line| {
+            line.address.is_some_and(|start| {
+                start <= address && (address as u64) < start as u64 + 4 * line.words.len() as u64
+            })
+        });
+        let line = line.map(|line| (line.line, line.text.clone()));
+        match violations.last_mut() {
+            Some(last) if last.line.is_some() && last.line == line => {
+                last.bytes.push((address, *byte))
+            }
+            Some(last) if line.is_none() && last.line.is_none() => {
+                last.bytes.push((address, *byte))
+            }
+            _ => violations.push(Violation {
+                line,
+                bytes: vec![(address, *byte)],
+                alternatives: vec![],
+            }),
+        }
+    }
+
+    for violation in violations.iter_mut() {
+        let Some((number, text)) = &violation.line else {
+            continue;
+        };
+        let address = listing.lines[number - 1].address.unwrap();
+        violation.alternatives =
+            alternatives(extensions, &listing.labels, address, text, forbidden);
+    }
+    violations
+}
+
+/// Rewrites of the instruction in `text`, and renamings of its registers,
+/// that assemble without `forbidden` bytes.
+fn alternatives(
+    extensions: &Extensions,
+    labels: &BTreeMap<String, u32>,
+    address: u32,
+    text: &str,
+    forbidden: &[u8],
+) -> Vec<String> {
+    let parse = |line: &str| extensions.parse(line).unwrap_or_else(|| line.parse()).ok();
+    let clean = |instructions: &[Instruction]| {
+        let mut asm = OutputAssembler::new(address, labels.clone());
+        asm.assemble(instructions).is_ok()
+            && asm.emitted.iter().all(|word| {
+                word.to_be_bytes()
+                    .iter()
+                    .all(|byte| !forbidden.contains(byte))
+            })
+    };
+    let Some(instruction) = parse(text) else {
+        return vec![];
+    };
+
+    let mut alternatives = vec![];
+    for rewrite in rewrites(&instruction) {
+        if clean(&rewrite) {
+            let rewrite = rewrite.iter().map(|i| i.to_string()).collect::<Vec<_>>();
+            alternatives.push(format!("`{}`", rewrite.join("; ")));
+        }
+    }
+
+    let (mnemonic, operands) = text.trim().split_once(' ').unwrap_or((text.trim(), ""));
+    let operands = operands.split(',').map(|p| p.trim()).collect::<Vec<_>>();
+    let registers = operands
+        .iter()
+        .filter_map(|operand| operand.parse::<Reg>().ok())
+        .filter(|reg| reg.0 != 0)
+        .fold(vec![], |mut registers, reg| {
+            if !registers.contains(&reg) {
+                registers.push(reg);
+            }
+            registers
+        });
+    for register in registers {
+        let renames = (1..32)
+            .map(Reg)
+            .filter(|other| *other != register)
+            .filter(|other| {
+                let operands = operands
+                    .iter()
+                    .map(|operand| match operand.parse::<Reg>() {
+                        Ok(reg) if reg == register => other.to_string(),
+                        _ => operand.to_string(),
+                    })
+                    .collect::<Vec<_>>();
+                parse(&format!("{} {}", mnemonic, operands.join(", ")))
+                    .is_some_and(|instruction| clean(&[instruction]))
+            })
+            .map(|other| other.to_string())
+            .collect::<Vec<_>>();
+        if !renames.is_empty() {
+            alternatives.push(format!(
+                "replacing {} with {}",
+                register,
+                renames.join(", ")
+            ));
+        }
+    }
+    alternatives
+}
+
+/// Instruction sequences with the same effect as `instruction`.
+fn rewrites(instruction: &Instruction) -> Vec<Vec<Instruction>> {
+    use Instruction::*;
+
+    let zero = Reg(0);
+    match instruction {
+        Set32(rd, imm) => set_rewrites(*rd, &[(2, imm.0 >> 16), (3, imm.0 & 0xffff)], imm.0),
+        Set64(rd, imm) => set_rewrites(
+            *rd,
+            &(0..4)
+                .map(|hw| (hw, SetImm::halfword(imm.0, hw)))
+                .collect::<Vec<_>>(),
+            imm.0,
+        ),
+        Addi(rd, Rs(rs), Simm(simm)) if *rs == zero && *simm >= 0 => {
+            vec![vec![Set3(*rd, Rs(zero), SetImm::Imm(Uimm(*simm as u64)))]]
+        }
+        Addi(rd, Rs(rs), Simm(0)) => vec![
+            vec![Add(*rd, Rs(*rs), Rt(zero))],
+            vec![Add(*rd, Rs(zero), Rt(*rs))],
+        ],
+        Add(rd, Rs(rs), Rt(rt)) => vec![vec![Add(*rd, Rs(*rt), Rt(*rs))]],
+        _ => vec![],
+    }
+}
+
+/// Loads of `value` by `setN` of `halfwords` in any order, with or without the zero ones.
+fn set_rewrites(rd: Rd, halfwords: &[(u32, u64)], value: u64) -> Vec<Vec<Instruction>> {
+    let nonzero = halfwords
+        .iter()
+        .copied()
+        .filter(|(_, imm)| *imm != 0)
+        .collect::<Vec<_>>();
+    let mut orders = permutations(halfwords);
+    if !nonzero.is_empty() && nonzero.len() < halfwords.len() {
+        orders.extend(permutations(&nonzero));
+    }
+
+    let mut rewrites = orders
+        .into_iter()
+        .map(|order| {
+            order
+                .into_iter()
+                .enumerate()
+                .map(|(index, (hw, imm))| {
+                    let rs = Rs(if index == 0 { Reg(0) } else { rd.0 });
+                    let imm = SetImm::Imm(Uimm(imm));
+                    match hw {
+                        0 => Instruction::Set0(rd, rs, imm),
+                        1 => Instruction::Set1(rd, rs, imm),
+                        2 => Instruction::Set2(rd, rs, imm),
+                        _ => Instruction::Set3(rd, rs, imm),
+                    }
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    if value < 0x8000 {
+        rewrites.push(vec![Instruction::Addi(rd, Rs(Reg(0)), Simm(value as i64))]);
+    }
+    rewrites
+}
+
+fn permutations<T: Copy>(items: &[T]) -> Vec<Vec<T>> {
+    if items.is_empty() {
+        return vec![vec![]];
+    }
+    let mut result = vec![];
+    for index in 0..items.len() {
+        let mut rest = items.to_vec();
+        let first = rest.remove(index);
+        for mut permutation in permutations(&rest) {
+            permutation.insert(0, first);
+            result.push(permutation);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn violations(source: &str, forbidden: &[u8]) -> Vec<Violation> {
+        let extensions = Extensions::default();
+        let listing = crate::listing::listing(0x1000, source).unwrap();
+        let (code, _) = crate::assemble(0x1000, source).unwrap();
+        check(&extensions, &listing, 0x1000, &code, forbidden)
+    }
+
+    #[test]
+    fn forbid_bytes_alternatives() {
+        let found = violations("set32 r1, 0x1010000\n", &[0x00]);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].bytes, [(0x1006, 0x00), (0x1007, 0x00)]);
+        assert_eq!(
+            found[0].to_string(),
+            "line 1: `set32 r1, 0x1010000` contains 0x00 at 0x1006, 0x00 at 0x1007\n    \
+             try `set2 r1, r0, 0x101`"
+        );
+
+        let found = violations("addi r1, r1, 0xa\n", &[0x21]);
+        let renames = (2..32).map(|n| format!("r{}", n)).collect::<Vec<_>>();
+        assert_eq!(
+            found[0].alternatives,
+            [format!("replacing r1 with {}", renames.join(", "))]
+        );
+
+        let found = violations("dword 0x1\n.org 0x1008\ndword 0x1\n", &[0x00]);
+        assert_eq!(found.len(), 3);
+        assert_eq!(found[1].line, None);
+        assert_eq!(found[1].bytes.len(), 4);
+        assert!(found[1].alternatives.is_empty());
+    }
+}
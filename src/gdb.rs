@@ -0,0 +1,403 @@
+//! A GDB remote serial protocol stub for the emulator.
+
+use std::{
+    io::{BufReader, Read, Write},
+    net::TcpListener,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+
+use crate::{
+    debugger::{Debugger, Pause},
+    emu::Stop,
+    fields::Reg,
+    utils::parse_hex_bytes,
+};
+
+/// The register number of the pc, after r0 to r31.
+const PC: usize = 32;
+
+/// The largest packet the client may send, as advertised in `qSupported`.
+const PACKET_SIZE: u64 = 0x4000;
+
+/// The registers of `g` packets, all 64-bit big-endian.
+pub fn target_xml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+         <target version=\"1.0\">\n<feature name=\"org.gnu.gdb.irisc.core\">\n",
+    );
+    for reg in 0..32 {
+        xml += &format!("<reg name=\"r{}\" bitsize=\"64\" type=\"int64\"/>\n", reg);
+    }
+    xml += "<reg name=\"pc\" bitsize=\"64\" type=\"code_ptr\"/>\n</feature>\n</target>\n";
+    xml
+}
+
+/// One connection to a GDB client.
+pub struct Session<'a, S> {
+    debugger: &'a mut Debugger,
+    stream: S,
+    ack: bool,
+}
+
+impl<'a, S: Read + Write> Session<'a, S> {
+    pub fn new(debugger: &'a mut Debugger, stream: S) -> Self {
+        Self {
+            debugger,
+            stream,
+            ack: true,
+        }
+    }
+
+    fn read_byte(&mut self) -> Result<Option<u8>> {
+        let mut byte = [0];
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    /// The next packet, `None` once the client disconnects.
+    fn receive(&mut self) -> Result<Option<String>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => break,
+                // Acks, and interrupts, which can only arrive while nothing runs.
+                Some(_) => continue,
+            }
+        }
+        let mut data = vec![];
+        loop {
+            match self.read_byte()?.context("Connection closed in a packet")? {
+                b'#' => break,
+                b'}' => data.push(self.read_byte()?.context("Connection closed")? ^ 0x20),
+                byte => data.push(byte),
+            }
+        }
+        let mut checksum = [0; 2];
+        self.stream.read_exact(&mut checksum)?;
+        let expected = u8::from_str_radix(std::str::from_utf8(&checksum)?, 16)?;
+        if self.ack {
+            let ok = data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == expected;
+            self.stream.write_all(if ok { b"+" } else { b"-" })?;
+            if !ok {
+                return self.receive();
+            }
+        }
+        Ok(Some(String::from_utf8_lossy(&data).into_owned()))
+    }
+
+    fn send(&mut self, data: &str) -> Result<()> {
+        let mut packet = vec![b'$'];
+        for byte in data.bytes() {
+            match byte {
+                b'#' | b'$' | b'}' | b'*' => packet.extend([b'}', byte ^ 0x20]),
+                byte => packet.push(byte),
+            }
+        }
+        let checksum = packet[1..]
+            .iter()
+            .fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        packet.extend(format!("#{:02x}", checksum).bytes());
+        self.stream.write_all(&packet)?;
+        self.stream.flush()?;
+        if self.ack {
+            // Resending on `-` isn't worth it over TCP.
+            self.read_byte()?;
+        }
+        Ok(())
+    }
+
+    /// Answers packets until the client detaches, kills or disconnects.
+    pub fn serve(&mut self) -> Result<()> {
+        while let Some(packet) = self.receive()? {
+            match packet.as_str() {
+                "D" => return self.send("OK"),
+                "k" => return Ok(()),
+                _ => {}
+            }
+            let reply = self.reply(&packet).unwrap_or_else(|_| "E01".to_string());
+            self.send(&reply)?;
+            if packet == "QStartNoAckMode" {
+                self.ack = false;
+            }
+        }
+        Ok(())
+    }
+
+    fn reply(&mut self, packet: &str) -> Result<String> {
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        Ok(match command {
+            "?" => "S05".to_string(),
+            "g" => (0..=PC)
+                .map(|reg| format!("{:016x}", self.register(reg)))
+                .collect(),
+            "G" => {
+                for (reg, value) in hex_words(args)?.into_iter().enumerate().take(PC + 1) {
+                    self.set_register(reg, value)?;
+                }
+                "OK".to_string()
+            }
+            "p" => format!("{:016x}", self.register(usize::from_str_radix(args, 16)?)),
+            "P" => {
+                let (reg, value) = args.split_once('=').context("No value")?;
+                let value = hex_words(value)?.first().copied().context("No value")?;
+                self.set_register(usize::from_str_radix(reg, 16)?, value)?;
+                "OK".to_string()
+            }
+            "m" => {
+                let (address, len) = address_len(args)?;
+                // Two hex digits per byte.
+                ensure!(len <= PACKET_SIZE / 2, "Too long");
+                (address..address.checked_add(len).context("Overflow")?)
+                    .map(|address| format!("{:02x}", self.debugger.emulator.peek(address, 1)))
+                    .collect()
+            }
+            "M" => {
+                let (range, data) = args.split_once(':').context("No data")?;
+                let (address, len) = address_len(range)?;
+                let bytes = parse_hex_bytes(data)?;
+                if bytes.len() as u64 != len {
+                    bail!("Length mismatch");
+                }
+                address.checked_add(len).context("Overflow")?;
+                for (offset, byte) in bytes.into_iter().enumerate() {
+                    self.debugger
+                        .emulator
+                        .store_memory(address + offset as u64, 1, byte as u64);
+                }
+                "OK".to_string()
+            }
+            "s" | "c" => {
+                if !args.is_empty() {
+                    self.debugger.emulator.pc = u32::from_str_radix(args, 16)?;
+                }
+                let pause = match command {
+                    "s" => self.debugger.step_n(1),
+                    _ => self.debugger.resume(),
+                };
+                stop_reply(&pause)
+            }
+            "Z" | "z" => {
+                let mut fields = args.split(',');
+                let kind = fields.next().context("No type")?;
+                let address = u64::from_str_radix(fields.next().context("No address")?, 16)?;
+                let len = u64::from_str_radix(fields.next().context("No kind")?, 16)?;
+                let insert = command == "Z";
+                match kind {
+                    "0" | "1" if insert => {
+                        self.debugger.breakpoints.insert(u32::try_from(address)?);
+                    }
+                    "0" | "1" => {
+                        self.debugger.breakpoints.remove(&u32::try_from(address)?);
+                    }
+                    "2" if insert => {
+                        self.debugger.watchpoints.insert(address, len);
+                    }
+                    "2" => {
+                        self.debugger.watchpoints.remove(&address);
+                    }
+                    _ => return Ok(String::new()),
+                }
+                "OK".to_string()
+            }
+            "H" => "OK".to_string(),
+            "q" | "Q" => self.query(packet),
+            _ => String::new(),
+        })
+    }
+
+    fn query(&self, packet: &str) -> String {
+        if let Some(annex) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let Some((offset, len)) = annex.split_once(',') else {
+                return "E01".to_string();
+            };
+            let (Ok(offset), Ok(len)) = (
+                usize::from_str_radix(offset, 16),
+                usize::from_str_radix(len, 16),
+            ) else {
+                return "E01".to_string();
+            };
+            if offset.checked_add(len).is_none() {
+                return "E01".to_string();
+            }
+            // Leave room for the `m` or `l`.
+            let end = offset + len.min(PACKET_SIZE as usize - 1);
+            let xml = target_xml();
+            let chunk = xml.get(offset.min(xml.len())..end.min(xml.len()));
+            let chunk = chunk.unwrap_or_default();
+            let more = end < xml.len();
+            return format!("{}{}", if more { "m" } else { "l" }, chunk);
+        }
+        match packet.split(':').next().unwrap_or_default() {
+            "qSupported" => format!(
+                "PacketSize={:x};qXfer:features:read+;QStartNoAckMode+",
+                PACKET_SIZE
+            ),
+            "QStartNoAckMode" => "OK".to_string(),
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    fn register(&self, reg: usize) -> u64 {
+        let emulator = &self.debugger.emulator;
+        match reg {
+            PC => emulator.pc as u64,
+            reg if reg < PC => emulator.reg(Reg(reg as u32)),
+            _ => 0,
+        }
+    }
+
+    fn set_register(&mut self, reg: usize, value: u64) -> Result<()> {
+        let emulator = &mut self.debugger.emulator;
+        match reg {
+            PC => emulator.pc = u32::try_from(value)?,
+            reg if reg < PC => emulator.set_reg(Reg(reg as u32), value),
+            _ => bail!("No register {}", reg),
+        }
+        Ok(())
+    }
+}
+
+fn stop_reply(pause: &Pause) -> String {
+    match pause {
+        Pause::Stepped | Pause::Breakpoint(_) => "S05".to_string(),
+        Pause::Watchpoint { watched, .. } => format!("T05watch:{:x};", watched),
+        Pause::Stopped(Stop::Returned) => "W00".to_string(),
+        Pause::Stopped(Stop::StepLimit) => "S02".to_string(),
+        Pause::Stopped(Stop::Unknown { .. }) => "S04".to_string(),
+    }
+}
+
+fn address_len(s: &str) -> Result<(u64, u64)> {
+    let (address, len) = s.split_once(',').context("No length")?;
+    Ok((
+        u64::from_str_radix(address, 16)?,
+        u64::from_str_radix(len, 16)?,
+    ))
+}
+
+/// Big-endian 64-bit values.
+fn hex_words(s: &str) -> Result<Vec<u64>> {
+    Ok(parse_hex_bytes(s)?
+        .chunks(8)
+        .map(|word| word.iter().fold(0, |value, byte| value << 8 | *byte as u64))
+        .collect())
+}
+
+/// Serves one client connecting to `listener`.
+pub fn serve(debugger: &mut Debugger, listener: &TcpListener) -> Result<()> {
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    let reader = BufReader::new(stream.try_clone()?);
+    Session::new(debugger, Duplex(reader, stream)).serve()
+}
+
+/// Reads from one half and writes to the other.
+struct Duplex<R, W>(R, W);
+
+impl<R: Read, W> Read for Duplex<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<R, W: Write> Write for Duplex<R, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.1.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.1.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpStream;
+
+    use super::*;
+    use crate::{emu::Emulator, extensions::Extensions};
+
+    /// A scripted client.
+    struct Client(TcpStream);
+
+    impl Client {
+        fn request(&mut self, data: &str) -> String {
+            let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+            write!(self.0, "${}#{:02x}", data, checksum).unwrap();
+            let mut byte = [0];
+            self.0.read_exact(&mut byte).unwrap();
+            assert_eq!(byte[0], b'+');
+            let mut reply = vec![];
+            self.0.read_exact(&mut byte).unwrap();
+            assert_eq!(byte[0], b'$');
+            loop {
+                self.0.read_exact(&mut byte).unwrap();
+                if byte[0] == b'#' {
+                    break;
+                }
+                reply.push(byte[0]);
+            }
+            let mut checksum = [0; 2];
+            self.0.read_exact(&mut checksum).unwrap();
+            self.0.write_all(b"+").unwrap();
+            String::from_utf8(reply).unwrap()
+        }
+    }
+
+    #[test]
+    fn gdb_session() {
+        let source = "set32 r4, 0x2000\naddi r1, r0, 7\nlbl store\nst.d r0, r4, r1, 0x0\n\
+                      addi r1, r1, 1\nret.d\n";
+        let (regions, labels) =
+            crate::assemble_regions_with(&Extensions::default(), 0x1000, source).unwrap();
+        let mut emulator = Emulator::new(Extensions::default());
+        emulator.load(&regions);
+        let mut debugger = Debugger::new(emulator, &regions, labels);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            serve(&mut debugger, &listener).unwrap();
+            debugger
+        });
+
+        let mut client = Client(TcpStream::connect(address).unwrap());
+        assert!(client
+            .request("qSupported:xmlRegisters=i386")
+            .contains("qXfer:features:read+"));
+        let xml = client.request("qXfer:features:read:target.xml:0,fff");
+        assert!(xml.starts_with("l<?xml") && xml.contains("name=\"r31\""));
+        assert_eq!(client.request("?"), "S05");
+        assert_eq!(client.request("Z0,100c,4"), "OK");
+        assert_eq!(client.request("c"), "S05");
+        assert_eq!(client.request("p20"), "000000000000100c");
+        assert_eq!(client.request("p1"), "0000000000000007");
+        assert_eq!(client.request("P1=0000000000000042"), "OK");
+        assert_eq!(client.request("s"), "S05");
+        assert_eq!(client.request("m2000,4"), "00000042");
+        assert_eq!(client.request("M2004,2:beef"), "OK");
+        assert_eq!(client.request("m2004,2"), "beef");
+        assert_eq!(client.request("mffffffffffffffff,2"), "E01");
+        assert_eq!(client.request("m0,ffffff"), "E01");
+        assert_eq!(client.request("Mffffffffffffffff,2:beef"), "E01");
+        assert_eq!(
+            client.request("qXfer:features:read:target.xml:1,ffffffffffffffff"),
+            "E01"
+        );
+        let registers = client.request("g");
+        assert_eq!(registers.len(), 33 * 16);
+        assert_eq!(&registers[4 * 16..5 * 16], "0000000000002000");
+        assert_eq!(client.request("z0,100c,4"), "OK");
+        assert_eq!(client.request("c"), "W00");
+        assert_eq!(client.request("D"), "OK");
+
+        let debugger = server.join().unwrap();
+        assert_eq!(debugger.emulator.registers[1], 0x43);
+    }
+}
@@ -0,0 +1,375 @@
+//! Inferring what unknown instructions do from hardware results.
+
+use std::{collections::BTreeMap, fmt};
+
+use anyhow::{ensure, Context};
+
+use crate::{
+    emu::{
+        semantics::{AluOp, Condition},
+        Flags,
+    },
+    verify::Observation,
+};
+
+/// How wide an operation is and how a 32-bit result is extended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Width {
+    W64,
+    /// The low 32 bits of the operands, sign-extending the result.
+    W32Signed,
+    /// The low 32 bits of the operands, zero-extending the result.
+    W32Unsigned,
+}
+
+/// An operation an `alu.r` funct might perform.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Candidate {
+    pub op: AluOp,
+    pub width: Width,
+}
+
+impl Candidate {
+    pub fn all() -> impl Iterator<Item = Candidate> {
+        AluOp::ALL.iter().flat_map(|op| {
+            [Width::W64, Width::W32Signed, Width::W32Unsigned]
+                .into_iter()
+                .map(|width| Candidate { op: *op, width })
+        })
+    }
+
+    pub fn apply(self, a: u64, b: u64) -> u64 {
+        match self.width {
+            Width::W64 => self.op.apply(a, b),
+            Width::W32Signed => self.op.apply32(a as u32, b as u32) as i32 as u64,
+            Width::W32Unsigned => self.op.apply32(a as u32, b as u32) as u64,
+        }
+    }
+}
+
+impl fmt::Display for Candidate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.width {
+            Width::W64 => write!(f, "{}", self.op),
+            Width::W32Signed => write!(f, "{}.w (sign-extended)", self.op),
+            Width::W32Unsigned => write!(f, "{}.w (zero-extended)", self.op),
+        }
+    }
+}
+
+/// One run of `alu.r funct, rd, rs, rt`: the operands and the result.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sample {
+    pub a: u64,
+    pub b: u64,
+    pub result: u64,
+}
+
+/// Where to find the funct, the operands and the result of each run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AluLayout {
+    /// The parameter holding the funct.
+    pub funct: String,
+    /// The parameters holding `rs` and `rt`.
+    pub lhs: String,
+    pub rhs: String,
+    /// Where in the output buffer `rd` is stored, as a 64-bit big-endian value.
+    pub result_offset: usize,
+}
+
+impl Default for AluLayout {
+    /// The layout of `64bit_alu_branch_test.asm`.
+    fn default() -> Self {
+        Self {
+            funct: "funct".to_string(),
+            lhs: "r5".to_string(),
+            rhs: "r6".to_string(),
+            result_offset: 0x18,
+        }
+    }
+}
+
+impl AluLayout {
+    /// Groups the samples of `observations` by funct.
+    pub fn samples(
+        &self,
+        observations: &[Observation],
+    ) -> anyhow::Result<BTreeMap<u64, Vec<Sample>>> {
+        let mut samples = BTreeMap::<u64, Vec<Sample>>::new();
+        for observation in observations {
+            let parameter = |name: &String| parameter(observation, name);
+            samples
+                .entry(parameter(&self.funct)?)
+                .or_default()
+                .push(Sample {
+                    a: parameter(&self.lhs)?,
+                    b: parameter(&self.rhs)?,
+                    result: read(observation, self.result_offset, 8)?,
+                });
+        }
+        Ok(samples)
+    }
+}
+
+/// The candidates that agree with the most samples, and how many that is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Inference<T> {
+    pub samples: usize,
+    pub matched: usize,
+    pub candidates: Vec<T>,
+    /// The indices of the samples the first candidate disagrees with.
+    pub mismatches: Vec<usize>,
+}
+
+impl<T: fmt::Display> fmt::Display for Inference<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let candidates = self
+            .candidates
+            .iter()
+            .map(|candidate| candidate.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let exact = self.matched == self.samples;
+        match (self.matched, exact, self.candidates.len()) {
+            (0, _, _) => write!(f, "no candidate matches any of {} samples", self.samples),
+            (_, true, 1) => write!(f, "{} ({} samples)", candidates, self.samples),
+            (_, true, _) => write!(f, "ambiguous: {} ({} samples)", candidates, self.samples),
+            (_, false, _) => write!(
+                f,
+                "no exact match, closest {} ({} of {} samples)",
+                candidates, self.matched, self.samples
+            ),
+        }
+    }
+}
+
+/// Tests every candidate against `samples`.
+fn infer<T: Copy, S>(
+    candidates: impl Iterator<Item = T>,
+    samples: &[S],
+    agrees: impl Fn(T, &S) -> bool,
+) -> Inference<T> {
+    let mut inference = Inference {
+        samples: samples.len(),
+        matched: 0,
+        candidates: vec![],
+        mismatches: vec![],
+    };
+    for candidate in candidates {
+        let matched = samples
+            .iter()
+            .filter(|sample| agrees(candidate, sample))
+            .count();
+        if matched > inference.matched {
+            inference.matched = matched;
+            inference.candidates.clear();
+        }
+        if matched == inference.matched && matched > 0 {
+            inference.candidates.push(candidate);
+        }
+    }
+    if let Some(best) = inference.candidates.first() {
+        inference.mismatches = (0..samples.len())
+            .filter(|index| !agrees(*best, &samples[*index]))
+            .collect();
+    }
+    inference
+}
+
+/// Finds the operations that explain `samples` of one funct.
+pub fn infer_alu(samples: &[Sample]) -> Inference<Candidate> {
+    infer(Candidate::all(), samples, |candidate, sample| {
+        candidate.apply(sample.a, sample.b) == sample.result
+    })
+}
+
+/// One run of a `b.t` or `b.f` after a flag-setting instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BranchSample {
+    pub parameters: BTreeMap<String, u64>,
+    pub flags: Flags,
+    /// Whether the condition held, rather than whether the branch was taken.
+    pub holds: bool,
+}
+
+/// Where to find the cmpop, the flags and whether the branch was taken.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BranchLayout {
+    /// The parameter holding the cmpop.
+    pub cmpop: String,
+    /// The parameters holding the operands of the flag-setting instruction.
+    pub lhs: String,
+    pub rhs: String,
+    /// Where in the output buffer its 64-bit result is stored.
+    pub result_offset: usize,
+    /// Where in the output buffer a 32-bit word is stored that is non-zero
+    /// if the branch was taken.
+    pub taken_offset: usize,
+    /// Whether the branch is `b.f`, taken when the condition doesn't hold.
+    pub negated: bool,
+}
+
+impl Default for BranchLayout {
+    /// The layout of `64bit_alu_branch_test.asm`.
+    fn default() -> Self {
+        Self {
+            cmpop: "jmpop".to_string(),
+            lhs: "r5".to_string(),
+            rhs: "r6".to_string(),
+            result_offset: 0x18,
+            taken_offset: 0x10,
+            negated: true,
+        }
+    }
+}
+
+impl BranchLayout {
+    /// Groups the samples of `observations` by cmpop.
+    pub fn samples(
+        &self,
+        observations: &[Observation],
+    ) -> anyhow::Result<BTreeMap<u64, Vec<BranchSample>>> {
+        let mut samples = BTreeMap::<u64, Vec<BranchSample>>::new();
+        for observation in observations {
+            let parameter = |name: &String| parameter(observation, name);
+            let flags = Flags {
+                lhs: parameter(&self.lhs)?,
+                rhs: parameter(&self.rhs)?,
+                result: read(observation, self.result_offset, 8)?,
+            };
+            let taken = read(observation, self.taken_offset, 4)? != 0;
+            samples
+                .entry(parameter(&self.cmpop)?)
+                .or_default()
+                .push(BranchSample {
+                    parameters: observation.parameters.clone(),
+                    flags,
+                    holds: taken != self.negated,
+                });
+        }
+        Ok(samples)
+    }
+}
+
+/// Finds the conditions that explain `samples` of one cmpop.
+pub fn infer_branch(samples: &[BranchSample]) -> Inference<Condition> {
+    infer(
+        Condition::ALL.iter().copied(),
+        samples,
+        |condition, sample| condition.holds(sample.flags) == sample.holds,
+    )
+}
+
+fn parameter(observation: &Observation, name: &str) -> anyhow::Result<u64> {
+    observation
+        .parameters
+        .get(name)
+        .copied()
+        .with_context(|| format!("No parameter {} in a result", name))
+}
+
+/// Reads a `len` byte big-endian value at `offset` of the output buffer.
+fn read(observation: &Observation, offset: usize, len: usize) -> anyhow::Result<u64> {
+    let output = &observation.output;
+    ensure!(
+        output.len() >= offset + len,
+        "A result of {} bytes has no value at {:#x}",
+        output.len(),
+        offset
+    );
+    Ok(output[offset..offset + len]
+        .iter()
+        .fold(0, |value, byte| value << 8 | *byte as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn observation(funct: u64, a: u64, b: u64, result: u64) -> Observation {
+        branch_observation(funct, 0, a, b, result, false)
+    }
+
+    fn branch_observation(
+        funct: u64,
+        cmpop: u64,
+        a: u64,
+        b: u64,
+        result: u64,
+        taken: bool,
+    ) -> Observation {
+        let parameters = [("funct", funct), ("jmpop", cmpop), ("r5", a), ("r6", b)]
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect();
+        let mut output = vec![0; 0x20];
+        output[0x10..0x14].copy_from_slice(&(taken as u32).to_be_bytes());
+        output[0x18..].copy_from_slice(&result.to_be_bytes());
+        Observation { parameters, output }
+    }
+
+    #[test]
+    fn infer_functs() {
+        let values = [
+            0,
+            1,
+            5,
+            0x8000_0000,
+            0xffff_ffff,
+            0x1234_5678_9abc_def0,
+            u64::MAX,
+        ];
+        let mut observations = vec![];
+        for a in values {
+            for b in values {
+                observations.push(observation(0x0, a, b, a.wrapping_add(b)));
+                observations.push(observation(0x1, a, b, (a as u32 ^ b as u32) as i32 as u64));
+                observations.push(observation(0x2, a, b, 7));
+            }
+        }
+        observations.push(observation(0x1, 3, 3, 1));
+
+        let samples = AluLayout::default().samples(&observations).unwrap();
+        let inferred = samples
+            .values()
+            .map(|samples| infer_alu(samples).to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(inferred[0], "add (49 samples)");
+        assert_eq!(
+            inferred[1],
+            "no exact match, closest xor.w (sign-extended) (49 of 50 samples)"
+        );
+        assert_eq!(inferred[2], "no candidate matches any of 49 samples");
+
+        let mut short = observation(0x0, 1, 2, 3);
+        short.output.truncate(0x10);
+        assert!(AluLayout::default().samples(&[short]).is_err());
+    }
+
+    #[test]
+    fn infer_cmpops() {
+        let values = [0, 1, 2, 0x8000_0000_0000_0000, u64::MAX];
+        let mut observations = vec![];
+        for a in values {
+            for b in values {
+                let result = a.wrapping_sub(b);
+                // b.f is taken when the condition doesn't hold.
+                for (cmpop, holds) in [(0x1, a == b), (0x2, (a as i64) < (b as i64)), (0x3, true)] {
+                    observations.push(branch_observation(0, cmpop, a, b, result, !holds));
+                }
+                observations.push(branch_observation(0, 0x4, a, b, result, a == 2));
+            }
+        }
+
+        let samples = BranchLayout::default().samples(&observations).unwrap();
+        let inferred = samples
+            .values()
+            .map(|samples| infer_branch(samples))
+            .collect::<Vec<_>>();
+        assert_eq!(inferred[0].to_string(), "ambiguous: eq, zero (25 samples)");
+        assert_eq!(inferred[1].to_string(), "lt (25 samples)");
+        assert_eq!(inferred[2].to_string(), "always (25 samples)");
+        assert!(inferred[3].to_string().starts_with("no exact match"));
+        assert!(!inferred[3].mismatches.is_empty());
+    }
+}
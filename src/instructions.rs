@@ -1,111 +1,362 @@
 use core::str::FromStr;
+use std::fmt;
 
 use anyhow::{bail, ensure, Context};
 
-use crate::fields::{Bits, Funct, Jmpop, Label, Memop, Off14, Off9, Opcode, Rd, Reg, Rs, Rt, Simm, StoreOff14, StoreOff16, Uimm};
-
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub enum Instruction {
-    Label(Label),
-    Dword(Uimm<32>),
-    Unki(Opcode, Rd, Rs, Uimm<16>),
-    Unkr(Opcode, Rd, Rs, Rt, Uimm<11>),
-    Unkst(Opcode, Rt, Rs, StoreOff14, Uimm<2>),
-    Addi(Rd, Rs, Simm<16>),
-    Jump(Label),
-    Call(Label),
-    Set0(Rd, Rs, Uimm<16>),
-    Set1(Rd, Rs, Uimm<16>),
-    Set2(Rd, Rs, Uimm<16>),
-    Set3(Rd, Rs, Uimm<16>),
-    Set32(Rd, Uimm<32>),
-    Set64(Rd, Uimm<64>),
-    Alur(Funct, Rd, Rs, Rt),
-    Add(Rd, Rs, Rt),
-    Sub(Rd, Rs, Rt),
-    Subs(Rd, Rs, Rt),
-    Retd,
-    Ldb(Rd, Rs, Simm<16>),
-    Ldq(Rd, Rs, Off14),
-    Lduw(Rd, Rs, Off14),
-    Ldd(Rd, Rs, Off14),
-    Ldlw(Rd, Rs, Off14),
-    Stb(Rt, Rs, StoreOff16),
-    Std(Rd, Rs, Rt, Off9),
-    Stq(Rd, Rs, Rt, Off9),
-    CsrR(Rd, Rs, Uimm<16>),
-    CsrW(Rd, Rs, Uimm<16>),
-}
+use crate::{
+    extensions::{ExtInstruction, Extensions},
+    fields::{
+        Bits, Bitsel, Cmpop, FieldBits, Funct, Jmpop, Label, Memop, Off14, Off9, Opcode, Operand,
+        Or, Rd, Reg, Rel, Rs, Rt, SetImm, Simm, StoreOff14, StoreOff16, Uimm,
+    },
+    object::RelocKind,
+    utils::parse_number,
+};
 
-fn check_indices<const N: usize>(indices: [usize; N]) {
-    assert_eq!(indices, std::array::from_fn(|i| i));
+/// Documentation of one row of the instruction table.
+#[derive(Debug, Clone, Copy)]
+pub struct InstructionSpec {
+    pub mnemonic: &'static str,
+    /// Operand names and types, in assembly order.
+    pub operands: &'static [(&'static str, &'static str)],
+    /// The fields fixed by the instruction, empty for pseudo-instructions.
+    pub encoding: &'static str,
+    pub doc: &'static str,
+    pub pseudo: bool,
+    /// The bits each fixed field and operand occupies, by name.
+    pub field_masks: fn() -> Vec<(&'static str, u32)>,
 }
 
-impl FromStr for Instruction {
-    type Err = anyhow::Error;
+/// Generates `Instruction` and everything derived from it from a table.
+///
+/// Each machine instruction row lists its operands and the fields it fixes;
+/// its encoding is the OR of both. `machine` rows are decoded in table order,
+/// so more specific encodings must come before the ones they overlap with.
+/// Rows in the `pseudo` block expand into other instructions through a
+/// function and are never decoded.
+macro_rules! instructions {
+    (
+        machine {
+            $(
+                $(#[doc = $doc:literal])*
+                $variant:ident $mnemonic:literal $(($($operand:ident: $ty:ty),*))? = [$($fixed:expr),*];
+            )*
+        }
+        pseudo {
+            $(
+                $(#[doc = $pdoc:literal])*
+                $pvariant:ident $pmnemonic:literal $(($($poperand:ident: $pty:ty),*))? => $expand:ident;
+            )*
+        }
+    ) => {
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub enum Instruction {
+            $(
+                $(#[doc = $doc])*
+                $variant $(($($ty),*))?,
+            )*
+            $(
+                $(#[doc = $pdoc])*
+                $pvariant $(($($pty),*))?,
+            )*
+            /// An instruction defined by an ISA extension.
+            Ext(ExtInstruction),
+        }
+
+        pub const INSTRUCTIONS: &[InstructionSpec] = &[
+            $(
+                InstructionSpec {
+                    mnemonic: $mnemonic,
+                    operands: &[$($((stringify!($operand), stringify!($ty))),*)?],
+                    encoding: stringify!($($fixed),*),
+                    doc: concat!($($doc, "\n"),*),
+                    pseudo: false,
+                    field_masks: || vec![
+                        $((stringify!($fixed), $fixed.mask()),)*
+                        $($((stringify!($operand), <$ty as Operand>::mask()),)*)?
+                    ],
+                },
+            )*
+            $(
+                InstructionSpec {
+                    mnemonic: $pmnemonic,
+                    operands: &[$($((stringify!($poperand), stringify!($pty))),*)?],
+                    encoding: "",
+                    doc: concat!($($pdoc, "\n"),*),
+                    pseudo: true,
+                    field_masks: Vec::new,
+                },
+            )*
+        ];
+
+        impl FromStr for Instruction {
+            type Err = anyhow::Error;
+
+            #[allow(unused_mut)]
+            fn from_str(line: &str) -> Result<Self, Self::Err> {
+                let line = line.trim();
+                let (cmd, rest) = line.split_once(' ').unwrap_or((line, ""));
+                let params = rest
+                    .trim()
+                    .split(',')
+                    .map(|p| p.trim())
+                    .filter(|p| !p.is_empty())
+                    .collect::<Vec<_>>();
+
+                let spec = INSTRUCTIONS
+                    .iter()
+                    .find(|spec| spec.mnemonic == cmd)
+                    .with_context(|| format!("Unknown instruction: {}", line))?;
+                ensure!(
+                    params.len() == spec.operands.len(),
+                    "Wrong number of parameters"
+                );
+                let mut params = params.into_iter();
+
+                Ok(match cmd {
+                    $(
+                        $mnemonic => Self::$variant $(($(params.next().unwrap().parse::<$ty>()?),*))?,
+                    )*
+                    $(
+                        $pmnemonic => Self::$pvariant $(($(params.next().unwrap().parse::<$pty>()?),*))?,
+                    )*
+                    _ => unreachable!(),
+                })
+            }
+        }
+
+        impl fmt::Display for Instruction {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                match self {
+                    $(
+                        Self::$variant $(($($operand),*))? => {
+                            write_operands(f, $mnemonic, &[$($($operand),*)?])
+                        }
+                    )*
+                    $(
+                        Self::$pvariant $(($($poperand),*))? => {
+                            write_operands(f, $pmnemonic, &[$($($poperand),*)?])
+                        }
+                    )*
+                    Self::Ext(ext) => ext.fmt(f),
+                }
+            }
+        }
+
+        impl Instruction {
+            pub fn mnemonic(&self) -> &str {
+                match self {
+                    $(Self::$variant { .. } => $mnemonic,)*
+                    $(Self::$pvariant { .. } => $pmnemonic,)*
+                    Self::Ext(ext) => &ext.def.mnemonic,
+                }
+            }
 
-    fn from_str(line: &str) -> Result<Self, Self::Err> {
-        use Instruction::*;
-
-        let line = line.trim();
-        let (cmd, rest) = line.split_once(' ').unwrap_or((line, &""));
-        let params = rest
-            .trim()
-            .split(',')
-            .map(|p| p.trim())
-            .filter(|p| !p.is_empty())
-            .collect::<Vec<_>>();
-
-        macro_rules! params {
-            ($variant:ident $( ( $($index:expr),* $(,)? ) )?) => {{
-                let indices = [$($($index),*)?];
-                check_indices(indices);
-                ensure!(params.len() == indices.len(), "Wrong number of parameters");
-                $variant $((
+            pub fn assemble<Asm: Assembler>(&self, asm: &mut Asm) -> Result<(), Asm::Err> {
+                match self {
+                    $(
+                        Self::$variant $(($($operand),*))? => {
+                            let word = FieldBits::EMPTY;
+                            $(let word = Or::new(word, $fixed);)*
+                            $($(let word = Or::new(word, $operand.encode(&*asm)?);)*)?
+                            asm.emit(word)?
+                        }
+                    )*
                     $(
-                        params[$index].parse()?
-                    ),*
-                ))?
-            }};
+                        Self::$pvariant $(($($poperand),*))? => $expand(asm $($(, $poperand)*)?)?,
+                    )*
+                    Self::Ext(ext) => ext.assemble(asm)?,
+                }
+
+                Ok(())
+            }
+
+            /// Decodes the instruction word found at `address`.
+            ///
+            /// Words that match no known encoding decode as `unk.i`.
+            pub fn decode(word: u32, address: u32) -> Self {
+                $(
+                    let candidate = Self::$variant $(($(<$ty as Operand>::decode(word, address)),*))?;
+                    if candidate.encodes_to(word, address) {
+                        return candidate;
+                    }
+                )*
+
+                unreachable!("unk.i decodes every word")
+            }
         }
+    };
+}
 
-        Ok(match cmd {
-            "lbl" => params!(Label(0)),
-            "dword" => params!(Dword(0)),
-            "unk.i" => params!(Unki(0, 1, 2, 3)),
-            "unk.r" => params!(Unkr(0, 1, 2, 3, 4)),
-            "unk.st" => params!(Unkst(0, 1, 2, 3, 4)),
-            "addi" => params!(Addi(0, 1, 2)),
-            "jump" => params!(Jump(0)),
-            "call" => params!(Call(0)),
-            "set0" => params!(Set0(0, 1, 2)),
-            "set1" => params!(Set1(0, 1, 2)),
-            "set2" => params!(Set2(0, 1, 2)),
-            "set3" => params!(Set3(0, 1, 2)),
-            "set32" => params!(Set32(0, 1)),
-            "set64" => params!(Set64(0, 1)),
-            "alu.r" => params!(Alur(0, 1, 2, 3)),
-            "add" => params!(Add(0, 1, 2)),
-            "sub" => params!(Sub(0, 1, 2)),
-            "subs" => params!(Subs(0, 1, 2)),
-            "ret.d" => params!(Retd),
-            "ld.b" => params!(Ldb(0, 1, 2)),
-            "ld.q" => params!(Ldq(0, 1, 2)),
-            "ld.uw" => params!(Lduw(0, 1, 2)),
-            "ld.d" => params!(Ldd(0, 1, 2)),
-            "ld.lw" => params!(Ldlw(0, 1, 2)),
-            "st.b" => params!(Stb(0, 1, 2)),
-            "st.d" => params!(Std(0, 1, 2, 3)),
-            "st.q" => params!(Stq(0, 1, 2, 3)),
-            "csr.r" => params!(CsrR(0, 1, 2)),
-            "csr.w" => params!(CsrW(0, 1, 2)),
-            _ => bail!("Unknown instruction: {}", line),
-        })
+instructions! {
+    machine {
+        /// Add a sign-extended immediate to `rs`.
+        Addi "addi" (rd: Rd, rs: Rs, simm: Simm<16>) = [Opcode::fixed(0x00)];
+        /// Replace bits 63:48 of `rs` with `imm`.
+        Set0 "set0" (rd: Rd, rs: Rs, imm: SetImm) = [Opcode::fixed(0x06)];
+        /// Replace bits 47:32 of `rs` with `imm`.
+        Set1 "set1" (rd: Rd, rs: Rs, imm: SetImm) = [Opcode::fixed(0x07)];
+        /// Replace bits 31:16 of `rs` with `imm`.
+        Set2 "set2" (rd: Rd, rs: Rs, imm: SetImm) = [Opcode::fixed(0x09)];
+        /// Replace bits 15:0 of `rs` with `imm`.
+        Set3 "set3" (rd: Rd, rs: Rs, imm: SetImm) = [Opcode::fixed(0x08)];
+        /// Read a control and status register.
+        CsrR "csr.r" (rd: Rd, rs: Rs, csr: Uimm<16>) = [Opcode::fixed(0x12)];
+        /// Write a control and status register.
+        CsrW "csr.w" (rd: Rd, rs: Rs, csr: Uimm<16>) = [Opcode::fixed(0x13)];
+        /// Load a byte.
+        Ldb "ld.b" (rd: Rd, rs: Rs, off: Simm<16>) = [Opcode::fixed(0x18)];
+        /// Load a 64-bit qword.
+        Ldq "ld.q" (rd: Rd, rs: Rs, off: Off14) = [Opcode::fixed(0x19), Memop::Qword];
+        /// Load a 32-bit word into the upper half of `rd`.
+        Lduw "ld.uw" (rd: Rd, rs: Rs, off: Off14) = [Opcode::fixed(0x19), Memop::UpperWord];
+        /// Load a 32-bit dword.
+        Ldd "ld.d" (rd: Rd, rs: Rs, off: Off14) = [Opcode::fixed(0x19), Memop::Dword];
+        /// Load a 32-bit word into the lower half of `rd`.
+        Ldlw "ld.lw" (rd: Rd, rs: Rs, off: Off14) = [Opcode::fixed(0x19), Memop::LowerWord];
+        /// Store the low byte of `rt`.
+        Stb "st.b" (rt: Rt, rs: Rs, off: StoreOff16) = [Opcode::fixed(0x1a)];
+        /// Store the low 32 bits of `rt`.
+        Std "st.d" (rd: Rd, rs: Rs, rt: Rt, off: Off9) = [Opcode::fixed(0x1b), Uimm::<2>(2)];
+        /// Store all 64 bits of `rt`.
+        Stq "st.q" (rd: Rd, rs: Rs, rt: Rt, off: Off9) = [Opcode::fixed(0x1e), Uimm::<2>(0)];
+        /// Jump to a label.
+        Jump "jump" (target: Rel<24>) = [Opcode::fixed(0x25), Jmpop::Jump];
+        /// Call a label.
+        Call "call" (target: Rel<24>) = [Opcode::fixed(0x25), Jmpop::Call];
+        /// Branch if condition `cmpop` of the flags holds.
+        Bt "b.t" (cmpop: Cmpop, rs: Rs, target: Rel<16>) = [Opcode::fixed(0x28)];
+        /// Branch if condition `cmpop` of the flags does not hold.
+        Bf "b.f" (cmpop: Cmpop, rs: Rs, target: Rel<16>) = [Opcode::fixed(0x29)];
+        /// Branch if bit `bitsel` of `rs` is set.
+        Bset "b.set" (rs: Rs, bitsel: Bitsel, target: Rel<16>) = [Opcode::fixed(0x2a)];
+        /// Branch if bit `bitsel` of `rs` is clear.
+        Bclr "b.clr" (rs: Rs, bitsel: Bitsel, target: Rel<16>) = [Opcode::fixed(0x2b)];
+        /// Add `rs` and `rt`.
+        Add "add" (rd: Rd, rs: Rs, rt: Rt) = [Opcode::fixed(0x3f), Funct::fixed(0x000)];
+        /// Subtract `rt` from `rs`.
+        Sub "sub" (rd: Rd, rs: Rs, rt: Rt) = [Opcode::fixed(0x3f), Funct::fixed(0x004)];
+        /// Subtract `rt` from `rs` and set the flags.
+        Subs "subs" (rd: Rd, rs: Rs, rt: Rt) = [Opcode::fixed(0x3f), Funct::fixed(0x005)];
+        /// Delayed return.
+        Retd "ret.d" = [
+            Opcode::fixed(0x3f), Rd(Reg(0)), Rs(Reg(0)), Rt(Reg(0)), Funct::fixed(0x02d)
+        ];
+        /// Register-register ALU operation selected by `funct`.
+        Alur "alu.r" (funct: Funct, rd: Rd, rs: Rs, rt: Rt) = [Opcode::fixed(0x3f)];
+        /// Unknown instruction in immediate format.
+        Unki "unk.i" (opcode: Opcode, rd: Rd, rs: Rs, imm: Uimm<16>) = [];
+        /// Unknown instruction in register format.
+        Unkr "unk.r" (opcode: Opcode, rd: Rd, rs: Rs, rt: Rt, imm: Uimm<11>) = [];
+        /// Unknown instruction in store format.
+        Unkst "unk.st" (opcode: Opcode, rt: Rt, rs: Rs, off: StoreOff14, width: Uimm<2>) = [];
+
+    }
+    pseudo {
+        /// Define a label at the current address.
+        Label "lbl" (name: Label) => label;
+        /// Emit a raw 32-bit word.
+        Dword "dword" (value: Uimm<32>) => dword;
+        /// Emit the address of a symbol, or a number.
+        Word ".word" (symbol: Label) => word;
+        /// Load a 32-bit constant with `set2` and `set3`.
+        Set32 "set32" (rd: Rd, imm: Uimm<32>) => set32;
+        /// Load a 64-bit constant with `set0` through `set3`.
+        Set64 "set64" (rd: Rd, imm: Uimm<64>) => set64;
+        /// Continue assembling at an absolute address.
+        Org ".org" (address: Uimm<32>) => org;
     }
 }
 
+fn write_operands(
+    f: &mut fmt::Formatter<'_>,
+    mnemonic: &str,
+    operands: &[&dyn fmt::Display],
+) -> fmt::Result {
+    f.write_str(mnemonic)?;
+    for (index, operand) in operands.iter().enumerate() {
+        let separator = if index == 0 { " " } else { ", " };
+        write!(f, "{}{}", separator, operand)?;
+    }
+    Ok(())
+}
+
+fn label<Asm: Assembler>(asm: &mut Asm, name: &Label) -> Result<(), Asm::Err> {
+    asm.label(&name.0, asm.current_address())
+}
+
+fn dword<Asm: Assembler>(asm: &mut Asm, value: &Uimm<32>) -> Result<(), Asm::Err> {
+    asm.emit(*value)
+}
+
+fn org<Asm: Assembler>(asm: &mut Asm, address: &Uimm<32>) -> Result<(), Asm::Err> {
+    asm.org(address.0 as u32)
+}
+
+fn word<Asm: Assembler>(asm: &mut Asm, symbol: &Label) -> Result<(), Asm::Err> {
+    let value = asm.reference(&symbol.0, RelocKind::Word)?;
+    asm.emit(Uimm::<32>(value as u64))
+}
+
+fn set32<Asm: Assembler>(asm: &mut Asm, rd: &Rd, uimm: &Uimm<32>) -> Result<(), Asm::Err> {
+    use Instruction::*;
+
+    Set2(*rd, Rs(Reg(0)), SetImm::Imm(Uimm((uimm.0 >> 16) & 0xffff))).assemble(asm)?;
+    Set3(*rd, Rs(rd.0), SetImm::Imm(Uimm(uimm.0 & 0xffff))).assemble(asm)
+}
+
+fn set64<Asm: Assembler>(asm: &mut Asm, rd: &Rd, uimm: &Uimm<64>) -> Result<(), Asm::Err> {
+    use Instruction::*;
+
+    Set0(*rd, Rs(Reg(0)), SetImm::Imm(Uimm((uimm.0 >> 48) & 0xffff))).assemble(asm)?;
+    Set1(*rd, Rs(rd.0), SetImm::Imm(Uimm((uimm.0 >> 32) & 0xffff))).assemble(asm)?;
+    Set2(*rd, Rs(rd.0), SetImm::Imm(Uimm((uimm.0 >> 16) & 0xffff))).assemble(asm)?;
+    Set3(*rd, Rs(rd.0), SetImm::Imm(Uimm(uimm.0 & 0xffff))).assemble(asm)
+}
+
+/// Renders the instruction table as a markdown table.
+pub fn documentation() -> String {
+    let mut doc = String::from("| Syntax | Operands | Encoding | Description |\n");
+    doc.push_str("|---|---|---|---|\n");
+    for spec in INSTRUCTIONS {
+        let syntax = spec
+            .operands
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(", ");
+        let syntax = if syntax.is_empty() {
+            spec.mnemonic.to_string()
+        } else {
+            format!("{} {}", spec.mnemonic, syntax)
+        };
+        let types = spec
+            .operands
+            .iter()
+            .map(|(name, ty)| format!("{}: {}", name, ty))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let encoding = match (spec.pseudo, spec.encoding) {
+            (true, _) => "pseudo".to_string(),
+            (false, "") => "-".to_string(),
+            (false, encoding) => format!("`{}`", encoding),
+        };
+        let description = spec
+            .doc
+            .lines()
+            .map(|line| line.trim())
+            .collect::<Vec<_>>()
+            .join(" ");
+        doc.push_str(&format!(
+            "| `{}` | {} | {} | {} |\n",
+            syntax, types, encoding, description
+        ));
+    }
+    doc
+}
+
 pub trait Assembler: Sized {
-    type Err;
+    type Err: From<anyhow::Error>;
 
     fn current_address(&self) -> u32;
 
@@ -113,8 +364,22 @@ pub trait Assembler: Sized {
 
     fn lookup(&self, name: &str) -> Result<u32, Self::Err>;
 
+    /// Resolves a symbol, or a number, that a `kind` field of the next word refers to.
+    ///
+    /// Assemblers that produce relocatable objects record a relocation here.
+    fn reference(&self, name: &str, _kind: RelocKind) -> Result<u32, Self::Err> {
+        match parse_number(name) {
+            Ok(value) => Ok(value as u32),
+            Err(_) => self.lookup(name),
+        }
+    }
+
     fn emit(&mut self, bits: impl Bits) -> Result<(), Self::Err>;
 
+    fn org(&mut self, address: u32) -> Result<(), Self::Err> {
+        Err(anyhow::anyhow!(".org {:#x} is not supported here", address).into())
+    }
+
     fn assemble(&mut self, instructions: &[Instruction]) -> Result<(), Self::Err> {
         for instruction in instructions {
             instruction.assemble(self)?
@@ -123,93 +388,76 @@ pub trait Assembler: Sized {
     }
 }
 
-impl Instruction {
-    pub fn assemble<Asm: Assembler>(&self, asm: &mut Asm) -> Result<(), Asm::Err> {
-        use Instruction::*;
-
-        match self.clone() {
-            Label(lbl) => asm.label(&lbl.0, asm.current_address())?,
-            Dword(dword) => asm.emit(dword)?,
-            Unki(op, rd, rs, uimm) => asm.emit(op | rd | rs | uimm)?,
-            Unkr(op, rd, rs, rt, uimm) => asm.emit(op | rd | rs | rt | uimm)?,
-            Unkst(op, rt, rs, off, width) => asm.emit(op | rt | rs | off | width)?,
-            Addi(rd, rs, simm) => asm.emit(Opcode::fixed(0x00) | rd | rs | simm)?,
-            Jump(lbl) => {
-                let offset: i32 = (asm.lookup(&lbl.0)? as i32 - asm.current_address() as i32) >> 2;
-                asm.emit(Opcode::fixed(0x25) | Jmpop::Jump | Simm::<24>::new(offset as i64).unwrap())?
-            }
-            Call(lbl) => {
-                let offset: i32 = (asm.lookup(&lbl.0)? as i32 - asm.current_address() as i32) >> 2;
-                asm.emit(Opcode::fixed(0x25) | Jmpop::Call | Simm::<24>::new(offset as i64).unwrap())?
-            }
-            Set0(rd, rs, uimm) => asm.emit(Opcode::fixed(0x06) | rd | rs | uimm)?,
-            Set1(rd, rs, uimm) => asm.emit(Opcode::fixed(0x07) | rd | rs | uimm)?,
-            Set3(rd, rs, uimm) => asm.emit(Opcode::fixed(0x08) | rd | rs | uimm)?,
-            Set2(rd, rs, uimm) => asm.emit(Opcode::fixed(0x09) | rd | rs | uimm)?,
-            Set64(rd, uimm) => {
-                Set0(rd, Rs(Reg(0)), Uimm((uimm.0 >> 48) & 0xffff)).assemble(asm)?;
-                Set1(rd, Rs(rd.0), Uimm((uimm.0 >> 32) & 0xffff)).assemble(asm)?;
-                Set2(rd, Rs(rd.0), Uimm((uimm.0 >> 16) & 0xffff)).assemble(asm)?;
-                Set3(rd, Rs(rd.0), Uimm(uimm.0 & 0xffff)).assemble(asm)?;
-            }
-            Set32(rd, uimm) => {
-                Set2(rd, Rs(Reg(0)), Uimm((uimm.0 >> 16) & 0xffff)).assemble(asm)?;
-                Set3(rd, Rs(rd.0), Uimm(uimm.0 & 0xffff)).assemble(asm)?;
-            }
-            Alur(funct, rd, rs, rt) => asm.emit(Opcode::fixed(0x3f) | rd | rs | rt | funct)?,
-            Add(rd, rs, rt) => {
-                asm.emit(Opcode::fixed(0x3f) | rd | rs | rt | Funct::fixed(0x000))?
-            }
-            Sub(rd, rs, rt) => {
-                asm.emit(Opcode::fixed(0x3f) | rd | rs | rt | Funct::fixed(0x004))?
-            }
-            Subs(rd, rs, rt) => {
-                asm.emit(Opcode::fixed(0x3f) | rd | rs | rt | Funct::fixed(0x005))?
-            }
-            Retd => asm.emit(Opcode::fixed(0x3f) | Funct::fixed(0x02d))?,
-            Ldb(rd, rs, simm16) => {
-                asm.emit(Opcode::fixed(0x18) | rd | rs | simm16)?
-            }
-            Ldq(rd, rs, off14) => {
-                asm.emit(Opcode::fixed(0x19) | rd | rs | off14 | Memop::Qword)?
-            }
-            Lduw(rd, rs, off14) => {
-                asm.emit(Opcode::fixed(0x19) | rd | rs | off14 | Memop::UpperWord)?
-            }
-            Ldd(rd, rs, off14) => {
-                asm.emit(Opcode::fixed(0x19) | rd | rs | off14 | Memop::Dword)?
-            }
-            Ldlw(rd, rs, off14) => {
-                asm.emit(Opcode::fixed(0x19) | rd | rs | off14 | Memop::LowerWord)?
-            }
-            Stb(rt, rs, stoff16) => {
-                asm.emit(Opcode::fixed(0x1a) | rs | rt | stoff16)?
-            }
-            Std(rd, rs, rt, off9) => {
-                asm.emit(Opcode::fixed(0x1b) | rd | rs | rt | off9 | Uimm::<2>(2))?
-            }
-            Stq(rd, rs, rt, off9) => {
-                asm.emit(Opcode::fixed(0x1e) | rd | rs | rt | off9 | Uimm::<2>(0))?
-            }
-            CsrR(rd, rs, uimm) => {
-                asm.emit(Opcode::fixed(0x12) | rd | rs | uimm)?
-            },
-            CsrW(rd, rs, uimm) => {
-                asm.emit(Opcode::fixed(0x13) | rd | rs | uimm)?
-            },
-        }
+/// Assembles a single instruction at a fixed address, for decoding.
+struct WordAssembler {
+    address: u32,
+    words: Vec<u32>,
+}
+
+impl Assembler for WordAssembler {
+    type Err = anyhow::Error;
+
+    fn current_address(&self) -> u32 {
+        self.address
+    }
 
+    fn label(&mut self, _name: &str, _address: u32) -> Result<(), Self::Err> {
         Ok(())
     }
 
+    fn lookup(&self, name: &str) -> Result<u32, Self::Err> {
+        bail!("label undefined: {}", name)
+    }
+
+    fn emit(&mut self, bits: impl Bits) -> Result<(), Self::Err> {
+        self.words.push(bits.bits());
+        Ok(())
+    }
+}
+
+impl Instruction {
+    pub(crate) fn encodes_to(&self, word: u32, address: u32) -> bool {
+        let mut asm = WordAssembler {
+            address,
+            words: vec![],
+        };
+        self.assemble(&mut asm).is_ok() && asm.words == [word]
+    }
+
+    /// Decodes like `decode`, trying the extension instructions first.
+    pub fn decode_with(word: u32, address: u32, extensions: &Extensions) -> Self {
+        extensions
+            .decode(word, address)
+            .unwrap_or_else(|| Self::decode(word, address))
+    }
+
     pub fn parse(source: &str) -> Result<Vec<Self>, anyhow::Error> {
+        Self::parse_with(source, &Extensions::default())
+    }
+
+    pub fn parse_with(source: &str, extensions: &Extensions) -> Result<Vec<Self>, anyhow::Error> {
+        Ok(Self::parse_lines_with(source, extensions)?
+            .into_iter()
+            .map(|(_, instruction)| instruction)
+            .collect())
+    }
+
+    /// Parses like `parse_with`, along with the line number each instruction is on.
+    pub fn parse_lines_with(
+        source: &str,
+        extensions: &Extensions,
+    ) -> Result<Vec<(usize, Self)>, anyhow::Error> {
         source
             .lines()
-            .map(|line| line.trim())
-            .filter(|line| !line.starts_with('#') && !line.is_empty())
-            .map(|line| {
-                line.parse()
-                    .with_context(|| format!("Bad instruction: {}", line))
+            .enumerate()
+            .map(|(index, line)| (index + 1, line.trim()))
+            .filter(|(_, line)| !line.starts_with('#') && !line.is_empty())
+            .map(|(number, line)| {
+                let instruction = extensions
+                    .parse(line)
+                    .unwrap_or_else(|| line.parse())
+                    .with_context(|| format!("Bad instruction: {}", line))?;
+                Ok((number, instruction))
             })
             .collect()
     }
@@ -361,4 +609,114 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn instruction_decode() {
+        assert_eq!(
+            Instruction::decode(0x0005fffc, 0),
+            Instruction::Addi(
+                "r5".parse().unwrap(),
+                "r0".parse().unwrap(),
+                Simm::new(-4).unwrap()
+            )
+        );
+        assert_eq!(
+            Instruction::decode(0x95fffffe, 0x1008),
+            Instruction::Jump("0x1000".parse().unwrap())
+        );
+        assert_eq!(Instruction::decode(0xfc00002d, 0), Instruction::Retd);
+        assert_eq!(
+            Instruction::decode(0xb0a51234, 0),
+            Instruction::Unki(
+                Opcode::fixed(0x2c),
+                "r5".parse().unwrap(),
+                "r5".parse().unwrap(),
+                Uimm(0x1234)
+            )
+        );
+    }
+
+    #[test]
+    fn instruction_decode_roundtrip() {
+        let source = r#"
+            lbl start
+            addi r5, r0, -0x10
+            set0 r5, r0, 0x1234
+            set1 r5, r5, 0x5678
+            set2 r5, r5, 0x9abc
+            set3 r5, r5, 0xdef0
+            csr.r r5, r0, 0x100
+            csr.w r0, r5, 0x100
+            ld.b r5, r4, -0x8
+            ld.q r5, r4, 0x10
+            ld.uw r5, r4, 0x14
+            ld.d r5, r4, 0x18
+            ld.lw r5, r4, 0x1c
+            st.b r5, r4, 0xffff
+            st.d r0, r4, r5, 0x8
+            st.q r0, r4, r7, 0x18
+            jump start
+            call end
+            b.t 0x1, r0, start
+            b.f 0x3, r5, end
+            b.set r5, 0x1f, start
+            b.clr r5, 0x0, end
+            add r7, r5, r6
+            sub r7, r5, r6
+            subs r0, r5, r6
+            ret.d
+            alu.r 0x00b, r7, r5, r6
+            unk.i 0x2c, r5, r6, 0xffff
+            lbl end
+        "#;
+        let (code, _) = crate::assemble(0x1000, source).unwrap();
+        let instructions = Instruction::parse(source).unwrap();
+        let instructions = instructions
+            .iter()
+            .filter(|instruction| !matches!(instruction, Instruction::Label(_)));
+
+        for ((index, word), instruction) in code.chunks(4).enumerate().zip(instructions) {
+            let address = 0x1000 + 4 * index as u32;
+            let word = u32::from_be_bytes(word.try_into().unwrap());
+            let decoded = Instruction::decode(word, address);
+            assert_eq!(decoded.mnemonic(), instruction.mnemonic());
+            assert_eq!(decoded.to_string().parse::<Instruction>().unwrap(), decoded);
+            assert!(decoded.encodes_to(word, address), "{}", decoded);
+        }
+    }
+
+    #[test]
+    fn instruction_documentation() {
+        let doc = documentation();
+        for spec in INSTRUCTIONS {
+            assert!(doc.contains(&format!("| `{}", spec.mnemonic)));
+        }
+        assert!(doc.contains(
+            "| `ld.q rd, rs, off` | rd: Rd, rs: Rs, off: Off14 | `Opcode::fixed(0x19), Memop::Qword` |"
+        ));
+    }
+
+    #[test]
+    fn instruction_fields_disjoint() {
+        for spec in INSTRUCTIONS.iter().filter(|spec| !spec.pseudo) {
+            let masks = (spec.field_masks)();
+            let mut used = 0;
+            for (name, mask) in masks.iter() {
+                let (other, _) = masks.iter().find(|(_, other)| other & mask != 0).unwrap();
+                assert!(
+                    used & mask == 0,
+                    "{}: {} overlaps {}",
+                    spec.mnemonic,
+                    name,
+                    other
+                );
+                used |= mask;
+            }
+            assert_eq!(
+                used, 0xffff_ffff,
+                "{}: not all bits are covered",
+                spec.mnemonic
+            );
+        }
+    }
 }
@@ -1,7 +1,24 @@
 pub mod assembler;
+pub mod debugger;
+pub mod emu;
+pub mod extensions;
 pub mod fields;
+pub mod forbid;
+pub mod gdb;
+pub mod infer;
 pub mod instructions;
+pub mod lint;
+pub mod listing;
+pub mod object;
+pub mod output;
+pub mod patch;
+pub mod pic;
+pub mod source_map;
 pub mod utils;
+pub mod verify;
 
-pub use assembler::{assemble, assemble_template};
+pub use assembler::{
+    assemble, assemble_regions_with, assemble_template, assemble_template_with, assemble_with,
+    render_template,
+};
 pub use instructions::Instruction;
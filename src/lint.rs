@@ -0,0 +1,118 @@
+//! Warnings about code that assembles but likely doesn't do what was meant.
+
+use std::{collections::BTreeMap, fmt};
+
+use crate::{
+    emu::delay::DelaySlots, extensions::Extensions, instructions::Instruction, listing::Listing,
+};
+
+/// A questionable source line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    /// The 1-based line number.
+    pub line: usize,
+    pub text: String,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "line {}: `{}`: {}",
+            self.line,
+            self.text.trim(),
+            self.message
+        )
+    }
+}
+
+/// Checks the delay slots after every control transfer in `listing`.
+///
+/// A slot should hold an instruction written for it: not another control
+/// transfer, not the end of the code, and not a label, which other code
+/// may reach without going through the transfer.
+pub fn delay_slots(extensions: &Extensions, listing: &Listing, slots: &DelaySlots) -> Vec<Warning> {
+    let mut words = BTreeMap::new();
+    for line in listing.lines.iter() {
+        let Some(address) = line.address else {
+            continue;
+        };
+        for (index, word) in line.words.iter().enumerate() {
+            words.insert(address.wrapping_add(4 * index as u32), (*word, line));
+        }
+    }
+
+    let mut warnings = vec![];
+    for (address, (word, line)) in words.iter() {
+        let instruction = Instruction::decode_with(*word, *address, extensions);
+        let Some(count) = slots.of(&instruction) else {
+            continue;
+        };
+        let mut warn = |message: String| {
+            warnings.push(Warning {
+                line: line.line,
+                text: line.text.clone(),
+                message,
+            })
+        };
+        for slot in 1..=count {
+            let slot_addr = address.wrapping_add(4 * slot);
+            let Some((slot_word, _)) = words.get(&slot_addr) else {
+                warn(format!(
+                    "delay slot {} at {:#x} is past the end of the code",
+                    slot, slot_addr
+                ));
+                break;
+            };
+            let slot_instruction = Instruction::decode_with(*slot_word, slot_addr, extensions);
+            if slots.of(&slot_instruction).is_some() {
+                warn(format!(
+                    "delay slot {} holds another control transfer `{}`",
+                    slot, slot_instruction
+                ));
+            }
+            if let Some((label, _)) = listing
+                .labels
+                .iter()
+                .find(|(_, address)| **address == slot_addr)
+            {
+                warn(format!(
+                    "delay slot {} is also the label `{}`, so it only implicitly belongs here",
+                    slot, label
+                ));
+            }
+        }
+    }
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listing::listing_with;
+
+    #[test]
+    fn lint_delay_slots() {
+        let source = "b.clr r1, 0, skip\naddi r1, r1, 1\njump out\nret.d\nlbl skip\nret.d\n\
+                      lbl out\naddi r2, r2, 1\nret.d\n";
+        let listing = listing_with(&Extensions::default(), 0, source).unwrap();
+        let warnings = delay_slots(&Extensions::default(), &listing, &"all=1".parse().unwrap())
+            .iter()
+            .map(|warning| warning.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            warnings,
+            [
+                "line 3: `jump out`: delay slot 1 holds another control transfer `ret.d`",
+                "line 4: `ret.d`: delay slot 1 holds another control transfer `ret.d`",
+                "line 4: `ret.d`: delay slot 1 is also the label `skip`, so it only implicitly \
+                 belongs here",
+                "line 6: `ret.d`: delay slot 1 is also the label `out`, so it only implicitly \
+                 belongs here",
+                "line 9: `ret.d`: delay slot 1 at 0x1c is past the end of the code",
+            ]
+        );
+        assert!(delay_slots(&Extensions::default(), &listing, &DelaySlots::default()).is_empty());
+    }
+}
@@ -0,0 +1,137 @@
+//! Listings showing the words each source line assembled to.
+
+use std::{collections::BTreeMap, fmt};
+
+use crate::{
+    assembler::{LabelAssembler, OutputAssembler, Region},
+    extensions::Extensions,
+    instructions::{Assembler, Instruction},
+};
+
+/// One source line with the address it was assembled at and its words.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListingLine {
+    /// The 1-based line number.
+    pub line: usize,
+    /// Where the words start, or where the next line continues for lines without words.
+    /// Only `None` for blank lines and comments.
+    pub address: Option<u32>,
+    pub words: Vec<u32>,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Listing {
+    pub lines: Vec<ListingLine>,
+    pub labels: BTreeMap<String, u32>,
+    /// The code, as `assemble_regions_with` returns it.
+    pub regions: Vec<Region>,
+}
+
+pub fn listing(base_addr: u32, source: &str) -> anyhow::Result<Listing> {
+    listing_with(&Extensions::default(), base_addr, source)
+}
+
+pub fn listing_with(
+    extensions: &Extensions,
+    base_addr: u32,
+    source: &str,
+) -> anyhow::Result<Listing> {
+    let instructions = Instruction::parse_lines_with(source, extensions)?;
+
+    let mut label_assembler = LabelAssembler::new(base_addr);
+    for (_, instruction) in instructions.iter() {
+        instruction.assemble(&mut label_assembler)?;
+    }
+
+    let mut output_assembler = OutputAssembler::new(base_addr, label_assembler.labels);
+    let mut lines = source
+        .lines()
+        .enumerate()
+        .map(|(index, text)| ListingLine {
+            line: index + 1,
+            address: None,
+            words: vec![],
+            text: text.to_string(),
+        })
+        .collect::<Vec<_>>();
+    for (line, instruction) in instructions.iter() {
+        let address = output_assembler.current_address();
+        instruction.assemble(&mut output_assembler)?;
+        let line = &mut lines[line - 1];
+        line.words = std::mem::take(&mut output_assembler.emitted);
+        line.address = Some(match line.words.is_empty() {
+            true => output_assembler.current_address(),
+            false => address,
+        });
+    }
+
+    let mut regions = output_assembler.regions;
+    regions.retain(|region| !region.code.is_empty());
+    Ok(Listing {
+        lines,
+        labels: output_assembler.labels,
+        regions,
+    })
+}
+
+impl fmt::Display for Listing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for line in self.lines.iter() {
+            let address = match line.address {
+                Some(address) => format!("{:08x}", address),
+                None => " ".repeat(8),
+            };
+            let word = match line.words.first() {
+                Some(word) => format!("{:08x}", word),
+                None => " ".repeat(8),
+            };
+            let text = format!("{:>5}  {}  {}  {}", line.line, address, word, line.text);
+            writeln!(f, "{}", text.trim_end())?;
+            for (index, word) in line.words.iter().enumerate().skip(1) {
+                let address = line.address.unwrap() + 4 * index as u32;
+                writeln!(f, "{:>5}  {:08x}  {:08x}", "", address, word)?;
+            }
+        }
+
+        writeln!(f)?;
+        writeln!(f, "Symbols:")?;
+        let mut labels = self.labels.iter().collect::<Vec<_>>();
+        labels.sort_by_key(|(name, address)| (**address, name.as_str()));
+        for (name, address) in labels {
+            writeln!(f, "{:08x}  {}", address, name)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listing_lines() {
+        let source = "# setup\nlbl start\nset64 r1, 0x1122334455667788\n\njump start\n";
+        let listing = listing(0x1000, source).unwrap();
+        let expected = [
+            "    1                      # setup",
+            "    2  00001000            lbl start",
+            "    3  00001000  18011122  set64 r1, 0x1122334455667788",
+            "       00001004  1c213344",
+            "       00001008  24215566",
+            "       0000100c  20217788",
+            "    4",
+            "    5  00001010  95fffffc  jump start",
+            "",
+            "Symbols:",
+            "00001000  start",
+            "",
+        ];
+        assert_eq!(listing.to_string(), expected.join("\n"));
+
+        let source = "addi r1, r0, 1\n.org 0x2000\nret.d\n";
+        let (regions, _) =
+            crate::assemble_regions_with(&Extensions::default(), 0x1000, source).unwrap();
+        assert_eq!(super::listing(0x1000, source).unwrap().regions, regions);
+    }
+}
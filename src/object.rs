@@ -0,0 +1,315 @@
+//! Relocatable objects, assembled at address 0 and placed by `link`.
+
+use std::{
+    cell::RefCell,
+    collections::{btree_map::Entry, BTreeMap},
+    path::Path,
+};
+
+use anyhow::{bail, ensure, Context};
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+
+use crate::{
+    assembler::LabelAssembler,
+    extensions::Extensions,
+    fields::{BitField, Bits, SetImm},
+    instructions::{Assembler, Instruction},
+    utils::parse_number,
+};
+
+/// How a symbol's address is placed into a word.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RelocKind {
+    /// A signed word offset from the word to the symbol, `bits` wide.
+    Rel(u32),
+    /// Halfword `N` of the address in the immediate of `setN`.
+    Hw(u32),
+    /// The whole word.
+    Word,
+}
+
+impl RelocKind {
+    /// Places `target` into `word`, which is at `address`.
+    pub fn apply(self, word: u32, address: u32, target: u32) -> anyhow::Result<u32> {
+        let (field, value) = match self {
+            RelocKind::Rel(bits) => (
+                BitField::new(0, bits).signed().scaled(2),
+                target.wrapping_sub(address) as i32 as i64,
+            ),
+            RelocKind::Hw(hw) => (
+                BitField::new(0, 16),
+                SetImm::halfword(target as u64, hw) as i64,
+            ),
+            RelocKind::Word => (BitField::new(0, 32), target as i64),
+        };
+        field.check(value)?;
+        Ok(word & !field.mask() | field.encode(value))
+    }
+}
+
+/// A reference to `symbol` from the word at `offset`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Relocation {
+    pub offset: u32,
+    pub kind: RelocKind,
+    pub symbol: String,
+}
+
+/// Code with its symbols as offsets and the references still to be resolved.
+#[serde_as]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Object {
+    #[serde_as(as = "serde_with::hex::Hex")]
+    pub code: Vec<u8>,
+    pub symbols: BTreeMap<String, u32>,
+    pub relocations: Vec<Relocation>,
+}
+
+impl Object {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        serde_json::from_str(&text).with_context(|| format!("Bad object {}", path.display()))
+    }
+}
+
+pub fn assemble_object(source: &str) -> anyhow::Result<Object> {
+    assemble_object_with(&Extensions::default(), source)
+}
+
+pub fn assemble_object_with(extensions: &Extensions, source: &str) -> anyhow::Result<Object> {
+    let instructions = Instruction::parse_with(source, extensions)?;
+
+    let mut label_assembler = LabelAssembler::new(0);
+    label_assembler.assemble(&instructions)?;
+
+    let mut object_assembler = ObjectAssembler {
+        object: Object {
+            symbols: label_assembler.labels,
+            ..Default::default()
+        },
+        relocations: Default::default(),
+    };
+    object_assembler.assemble(&instructions)?;
+
+    let mut object = object_assembler.object;
+    object.relocations = object_assembler.relocations.into_inner();
+    Ok(object)
+}
+
+/// Assembles at offset 0, leaving every address that depends on placement to `link`.
+struct ObjectAssembler {
+    object: Object,
+    relocations: RefCell<Vec<Relocation>>,
+}
+
+impl Assembler for ObjectAssembler {
+    type Err = anyhow::Error;
+
+    fn current_address(&self) -> u32 {
+        self.object.code.len() as u32
+    }
+
+    fn label(&mut self, name: &str, address: u32) -> Result<(), Self::Err> {
+        if let Some(label_address) = self.object.symbols.get(name) {
+            ensure!(*label_address == address, "Label redefined");
+        }
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str) -> Result<u32, Self::Err> {
+        if let Some(address) = self.object.symbols.get(name) {
+            return Ok(*address);
+        }
+        bail!("label undefined");
+    }
+
+    fn reference(&self, name: &str, kind: RelocKind) -> Result<u32, Self::Err> {
+        match (kind, self.object.symbols.get(name), parse_number(name)) {
+            (RelocKind::Rel(_), Some(offset), _) => return Ok(*offset),
+            (RelocKind::Hw(_) | RelocKind::Word, None, Ok(value)) => return Ok(value as u32),
+            _ => {}
+        }
+        self.relocations.borrow_mut().push(Relocation {
+            offset: self.current_address(),
+            kind,
+            symbol: name.to_string(),
+        });
+        match kind {
+            RelocKind::Rel(_) => Ok(self.current_address()),
+            RelocKind::Hw(_) | RelocKind::Word => Ok(0),
+        }
+    }
+
+    fn emit(&mut self, bits: impl Bits) -> Result<(), Self::Err> {
+        self.object
+            .code
+            .extend_from_slice(&bits.bits().to_be_bytes());
+        Ok(())
+    }
+
+    fn org(&mut self, address: u32) -> Result<(), Self::Err> {
+        bail!(".org {:#x} is not supported in objects", address)
+    }
+}
+
+/// Places `objects` one after another from `base_addr` and resolves their references.
+///
+/// Every symbol is global, so a name defined by two objects is an error, as
+/// is a reference no object defines. Both are reported for all objects at once.
+pub fn link(
+    base_addr: u32,
+    objects: &[(String, Object)],
+) -> anyhow::Result<(Vec<u8>, BTreeMap<String, u32>)> {
+    let mut addresses = vec![];
+    let mut symbols = BTreeMap::new();
+    let mut definitions = BTreeMap::new();
+    let mut errors = vec![];
+    let mut address = base_addr as u64;
+    for (name, object) in objects {
+        addresses.push(address as u32);
+        let end = address + object.code.len() as u64;
+        ensure!(
+            end <= 1 << 32,
+            "{} runs past the end of the address space",
+            name
+        );
+        for (symbol, offset) in object.symbols.iter() {
+            match definitions.entry(symbol.clone()) {
+                Entry::Vacant(entry) => {
+                    entry.insert(name);
+                    let Some(value) = (address as u32).checked_add(*offset) else {
+                        errors.push(format!(
+                            "Symbol {} in {} is past the end of the address space",
+                            symbol, name
+                        ));
+                        continue;
+                    };
+                    symbols.insert(symbol.clone(), value);
+                }
+                Entry::Occupied(entry) => errors.push(format!(
+                    "Duplicate symbol {} in {} and {}",
+                    symbol,
+                    entry.get(),
+                    name
+                )),
+            }
+        }
+        address = end;
+    }
+
+    let mut code = vec![];
+    for ((name, object), address) in objects.iter().zip(addresses) {
+        let start = code.len();
+        code.extend_from_slice(&object.code);
+        for relocation in object.relocations.iter() {
+            let target = match symbols.get(&relocation.symbol) {
+                Some(target) => *target,
+                None => match parse_number(&relocation.symbol) {
+                    Ok(value) => value as u32,
+                    Err(_) => {
+                        let error = format!(
+                            "Undefined symbol {} referenced from {}",
+                            relocation.symbol, name
+                        );
+                        if !errors.contains(&error) {
+                            errors.push(error);
+                        }
+                        continue;
+                    }
+                },
+            };
+            let offset = start + relocation.offset as usize;
+            let bytes = code
+                .get_mut(offset..offset + 4)
+                .with_context(|| format!("Relocation past the end of {}", name))?;
+            let word = u32::from_be_bytes((*bytes).try_into().unwrap());
+            let word = relocation
+                .kind
+                .apply(word, address + relocation.offset, target)
+                .with_context(|| {
+                    format!(
+                        "Cannot reach {} from {}+{:#x}",
+                        relocation.symbol, name, relocation.offset
+                    )
+                })?;
+            bytes.copy_from_slice(&word.to_be_bytes());
+        }
+    }
+
+    ensure!(errors.is_empty(), "{}", errors.join("\n"));
+    Ok((code, symbols))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link_sources(base_addr: u32, sources: &[&str]) -> anyhow::Result<Vec<u8>> {
+        let objects = sources
+            .iter()
+            .enumerate()
+            .map(|(index, source)| Ok((format!("{}.o", index), assemble_object(source)?)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(link(base_addr, &objects)?.0)
+    }
+
+    #[test]
+    fn object_relocations() {
+        let object = assemble_object(
+            "lbl main\ncall helper\njump main\nset3 r1, r0, %hw3(main)\n.word helper\n",
+        )
+        .unwrap();
+        assert_eq!(object.symbols, BTreeMap::from([("main".to_string(), 0)]));
+        assert_eq!(
+            object.relocations,
+            [
+                Relocation {
+                    offset: 0,
+                    kind: RelocKind::Rel(24),
+                    symbol: "helper".to_string()
+                },
+                Relocation {
+                    offset: 8,
+                    kind: RelocKind::Hw(3),
+                    symbol: "main".to_string()
+                },
+                Relocation {
+                    offset: 12,
+                    kind: RelocKind::Word,
+                    symbol: "helper".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn object_link() {
+        let main = "lbl main\ncall helper\nset2 r1, r0, %hw2(data)\nset3 r1, r1, %hw3(data)\n.word helper\n";
+        let helper = "lbl helper\nret.d\nlbl data\ndword 0x1234\n";
+        let linked = link_sources(0x10000, &[main, helper]).unwrap();
+        let (expected, _) = crate::assemble(0x10000, &format!("{}{}", main, helper)).unwrap();
+        assert_eq!(linked, expected);
+    }
+
+    #[test]
+    fn object_link_errors() {
+        let err = link_sources(0, &["lbl a\ncall b\njump b\n", "lbl a\n.word c\n"]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Duplicate symbol a in 0.o and 1.o\n\
+             Undefined symbol b referenced from 0.o\n\
+             Undefined symbol c referenced from 1.o"
+        );
+        assert!(assemble_object(".org 0x100\n").is_err());
+
+        let err = link_sources(0xffff_fffc, &["ret.d\nlbl end\n"]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Symbol end in 0.o is past the end of the address space"
+        );
+        assert!(link_sources(0xffff_fffc, &["ret.d\n", "ret.d\n"]).is_err());
+    }
+}
@@ -0,0 +1,401 @@
+//! Big-endian ELF executables with one `.text` section per region.
+
+use std::collections::BTreeMap;
+
+use anyhow::{ensure, Context, Result};
+
+use crate::assembler::Region;
+
+/// Not assigned to any real architecture.
+pub const DEFAULT_MACHINE: u16 = 0x4952;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_R: u32 = 4;
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHF_ALLOC: u64 = 2;
+const SHF_EXECINSTR: u64 = 4;
+const SHN_ABS: u16 = 0xfff1;
+const STB_GLOBAL: u8 = 1;
+
+/// Appends big-endian fields, with addresses sized by the ELF class.
+struct Writer {
+    is64: bool,
+    data: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn addr(&mut self, value: u64) {
+        match self.is64 {
+            true => self.data.extend_from_slice(&value.to_be_bytes()),
+            false => self.u32(value as u32),
+        }
+    }
+
+    fn align(&mut self, align: usize) {
+        self.data.resize(self.data.len().next_multiple_of(align), 0);
+    }
+}
+
+struct StringTable(Vec<u8>);
+
+impl StringTable {
+    fn new() -> Self {
+        Self(vec![0])
+    }
+
+    fn add(&mut self, name: &str) -> u32 {
+        let offset = self.0.len() as u32;
+        self.0.extend_from_slice(name.as_bytes());
+        self.0.push(0);
+        offset
+    }
+}
+
+struct Section {
+    name: u32,
+    kind: u32,
+    flags: u64,
+    addr: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+    entsize: u64,
+}
+
+/// Writes an executable loading `regions`, with `labels` as global symbols.
+pub fn write(
+    is64: bool,
+    machine: u16,
+    entry: u32,
+    regions: &[Region],
+    labels: &BTreeMap<String, u32>,
+) -> Vec<u8> {
+    let (ehsize, phentsize, shentsize) = match is64 {
+        true => (64, 56, 64),
+        false => (52, 32, 40),
+    };
+    let mut elf = Writer {
+        is64,
+        data: vec![0; ehsize + phentsize * regions.len()],
+    };
+    let mut shstrtab = StringTable::new();
+    let mut sections = vec![Section {
+        name: 0,
+        kind: 0,
+        flags: 0,
+        addr: 0,
+        offset: 0,
+        size: 0,
+        link: 0,
+        info: 0,
+        align: 0,
+        entsize: 0,
+    }];
+
+    for (index, region) in regions.iter().enumerate() {
+        let name = match index {
+            0 => ".text".to_string(),
+            _ => format!(".text.{}", index),
+        };
+        elf.align(4);
+        sections.push(Section {
+            name: shstrtab.add(&name),
+            kind: SHT_PROGBITS,
+            flags: SHF_ALLOC | SHF_EXECINSTR,
+            addr: region.address as u64,
+            offset: elf.data.len() as u64,
+            size: region.code.len() as u64,
+            link: 0,
+            info: 0,
+            align: 4,
+            entsize: 0,
+        });
+        elf.data.extend_from_slice(&region.code);
+    }
+
+    let mut strtab = StringTable::new();
+    elf.align(8);
+    let symtab_offset = elf.data.len();
+    elf.data
+        .resize(symtab_offset + if is64 { 24 } else { 16 }, 0);
+    for (name, address) in labels {
+        let name = strtab.add(name);
+        let shndx = regions
+            .iter()
+            .position(|region| region.address <= *address && (*address as u64) < region.end())
+            .map(|index| index as u16 + 1)
+            .unwrap_or(SHN_ABS);
+        elf.u32(name);
+        if is64 {
+            elf.u8(STB_GLOBAL << 4);
+            elf.u8(0);
+            elf.u16(shndx);
+            elf.addr(*address as u64);
+            elf.addr(0);
+        } else {
+            elf.u32(*address);
+            elf.u32(0);
+            elf.u8(STB_GLOBAL << 4);
+            elf.u8(0);
+            elf.u16(shndx);
+        }
+    }
+    let symtab_index = sections.len() as u32;
+    sections.push(Section {
+        name: shstrtab.add(".symtab"),
+        kind: SHT_SYMTAB,
+        flags: 0,
+        addr: 0,
+        offset: symtab_offset as u64,
+        size: (elf.data.len() - symtab_offset) as u64,
+        link: symtab_index + 1,
+        info: 1,
+        align: if is64 { 8 } else { 4 },
+        entsize: if is64 { 24 } else { 16 },
+    });
+
+    for (name, table) in [(".strtab", Some(strtab)), (".shstrtab", None)] {
+        let name = shstrtab.add(name);
+        let table = table.as_ref().unwrap_or(&shstrtab);
+        sections.push(Section {
+            name,
+            kind: SHT_STRTAB,
+            flags: 0,
+            addr: 0,
+            offset: elf.data.len() as u64,
+            size: table.0.len() as u64,
+            link: 0,
+            info: 0,
+            align: 1,
+            entsize: 0,
+        });
+        elf.data.extend_from_slice(&table.0);
+    }
+
+    elf.align(8);
+    let shoff = elf.data.len();
+    for section in sections.iter() {
+        elf.u32(section.name);
+        elf.u32(section.kind);
+        elf.addr(section.flags);
+        elf.addr(section.addr);
+        elf.addr(section.offset);
+        elf.addr(section.size);
+        elf.u32(section.link);
+        elf.u32(section.info);
+        elf.addr(section.align);
+        elf.addr(section.entsize);
+    }
+
+    let mut header = Writer {
+        is64,
+        data: b"\x7fELF".to_vec(),
+    };
+    header.u8(if is64 { 2 } else { 1 });
+    header.u8(2);
+    header.u8(1);
+    header.data.resize(16, 0);
+    header.u16(2);
+    header.u16(machine);
+    header.u32(1);
+    header.addr(entry as u64);
+    header.addr(ehsize as u64);
+    header.addr(shoff as u64);
+    header.u32(0);
+    header.u16(ehsize as u16);
+    header.u16(phentsize as u16);
+    header.u16(regions.len() as u16);
+    header.u16(shentsize as u16);
+    header.u16(sections.len() as u16);
+    header.u16(sections.len() as u16 - 1);
+
+    for section in sections
+        .iter()
+        .filter(|section| section.kind == SHT_PROGBITS)
+    {
+        header.u32(PT_LOAD);
+        if is64 {
+            header.u32(PF_R | PF_X);
+        }
+        header.addr(section.offset);
+        header.addr(section.addr);
+        header.addr(section.addr);
+        header.addr(section.size);
+        header.addr(section.size);
+        if !is64 {
+            header.u32(PF_R | PF_X);
+        }
+        header.addr(4);
+    }
+    elf.data[..header.data.len()].copy_from_slice(&header.data);
+    elf.data
+}
+
+/// `base + index * size`, failing on overflow.
+fn offset(base: u64, index: u64, size: u64) -> Result<u64> {
+    index
+        .checked_mul(size)
+        .and_then(|index| base.checked_add(index))
+        .context("Truncated ELF file")
+}
+
+/// Reads big-endian fields at an offset, sized like `Writer`.
+struct Reader<'a> {
+    is64: bool,
+    data: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn bytes(&self, offset: u64, len: u64) -> Result<&[u8]> {
+        let end = offset.checked_add(len).context("Truncated ELF file")?;
+        self.data
+            .get(offset as usize..end as usize)
+            .context("Truncated ELF file")
+    }
+
+    fn u16(&self, offset: u64) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.bytes(offset, 2)?.try_into()?))
+    }
+
+    fn u32(&self, offset: u64) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.bytes(offset, 4)?.try_into()?))
+    }
+
+    fn addr(&self, offset: u64) -> Result<u64> {
+        match self.is64 {
+            true => Ok(u64::from_be_bytes(self.bytes(offset, 8)?.try_into()?)),
+            false => Ok(self.u32(offset)? as u64),
+        }
+    }
+
+    fn string(&self, offset: u64) -> Result<String> {
+        let bytes = self
+            .data
+            .get(offset as usize..)
+            .context("Truncated ELF file")?;
+        let len = bytes
+            .iter()
+            .position(|byte| *byte == 0)
+            .context("Unterminated string")?;
+        Ok(String::from_utf8_lossy(&bytes[..len]).into_owned())
+    }
+}
+
+/// Reads the loadable segments and the symbols of a big-endian ELF file.
+pub fn read(data: &[u8]) -> Result<(Vec<Region>, BTreeMap<String, u32>)> {
+    ensure!(data.starts_with(b"\x7fELF"), "Not an ELF file");
+    ensure!(data.len() > 16 && data[5] == 2, "Not a big-endian ELF file");
+    let elf = Reader {
+        is64: data[4] == 2,
+        data,
+    };
+    let a = if elf.is64 { 8 } else { 4 };
+
+    let phoff = elf.addr(24 + a)?;
+    let shoff = elf.addr(24 + 2 * a)?;
+    let phentsize = elf.u16(30 + 3 * a)? as u64;
+    let phnum = elf.u16(32 + 3 * a)? as u64;
+    let shentsize = elf.u16(34 + 3 * a)? as u64;
+    let shnum = elf.u16(36 + 3 * a)? as u64;
+
+    let mut regions = vec![];
+    for index in 0..phnum {
+        let phdr = offset(phoff, index, phentsize)?;
+        if elf.u32(phdr)? != PT_LOAD {
+            continue;
+        }
+        let fields = offset(phdr, 1, a)?;
+        let start = elf.addr(fields)?;
+        let address = elf.addr(offset(fields, 1, a)?)?;
+        let size = elf.addr(offset(fields, 3, a)?)?;
+        regions.push(Region {
+            address: address as u32,
+            code: elf.bytes(start, size)?.to_vec(),
+        });
+    }
+
+    let mut symbols = BTreeMap::new();
+    let section = |index: u64| -> Result<(u32, u64, u64, u32)> {
+        let shdr = offset(shoff, index, shentsize)?;
+        let fields = offset(shdr, 1, 8)?;
+        Ok((
+            elf.u32(offset(shdr, 1, 4)?)?,
+            elf.addr(offset(fields, 2, a)?)?,
+            elf.addr(offset(fields, 3, a)?)?,
+            elf.u32(offset(fields, 4, a)?)?,
+        ))
+    };
+    for index in 0..shnum {
+        let (kind, start, size, link) = section(index)?;
+        if kind != SHT_SYMTAB {
+            continue;
+        }
+        let (_, strtab, _, _) = section(link as u64)?;
+        let entsize = if elf.is64 { 24 } else { 16 };
+        for sym in (start..offset(start, 1, size)?).step_by(entsize).skip(1) {
+            let name = elf.string(offset(strtab, 1, elf.u32(sym)? as u64)?)?;
+            let value = match elf.is64 {
+                true => elf.addr(offset(sym, 1, 8)?)?,
+                false => elf.u32(offset(sym, 1, 4)?)? as u64,
+            };
+            if !name.is_empty() {
+                symbols.insert(name, value as u32);
+            }
+        }
+    }
+
+    Ok((regions, symbols))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn elf_roundtrip() {
+        let regions = vec![
+            Region {
+                address: 0x1000,
+                code: vec![0x00, 0x01, 0x00, 0x01],
+            },
+            Region {
+                address: 0x2000,
+                code: vec![0x95, 0x00, 0x00, 0x00],
+            },
+        ];
+        let labels = BTreeMap::from([("start".to_string(), 0x1000), ("far".to_string(), 0x2000)]);
+        for is64 in [false, true] {
+            let data = write(is64, DEFAULT_MACHINE, 0x1000, &regions, &labels);
+            assert_eq!(&data[..6], &[0x7f, b'E', b'L', b'F', 1 + is64 as u8, 2]);
+            assert_eq!(&data[18..20], &DEFAULT_MACHINE.to_be_bytes());
+            assert_eq!(read(&data).unwrap(), (regions.clone(), labels.clone()));
+        }
+        assert!(read(b"\x7fELF\x01\x01").is_err());
+
+        // Header offsets that overflow are truncation, not a panic.
+        let data = write(true, DEFAULT_MACHINE, 0x1000, &regions, &labels);
+        for field in [32, 40] {
+            let mut data = data.clone();
+            data[field..field + 8].fill(0xff);
+            let err = read(&data).unwrap_err();
+            assert_eq!(err.to_string(), "Truncated ELF file");
+        }
+    }
+}
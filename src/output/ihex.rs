@@ -0,0 +1,122 @@
+//! Intel HEX, using extended linear address records for 32-bit addresses.
+
+use std::io::Write;
+
+use anyhow::{ensure, Context, Result};
+
+use super::push_bytes;
+use crate::assembler::Region;
+use crate::utils::parse_hex_bytes;
+
+fn record(out: &mut dyn Write, kind: u8, address: u16, data: &[u8]) -> Result<()> {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend_from_slice(&address.to_be_bytes());
+    bytes.push(kind);
+    bytes.extend_from_slice(data);
+    let checksum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    bytes.push(checksum.wrapping_neg());
+
+    write!(out, ":")?;
+    for byte in bytes {
+        write!(out, "{:02X}", byte)?;
+    }
+    writeln!(out)?;
+    Ok(())
+}
+
+/// Writes `regions` as data records, followed by `entry` as the start address.
+pub fn write(out: &mut dyn Write, regions: &[Region], entry: u32) -> Result<()> {
+    let mut upper = None;
+    for region in regions {
+        let mut offset = 0;
+        while offset < region.code.len() {
+            let address = region.address + offset as u32;
+            let len = (region.code.len() - offset)
+                .min(16)
+                .min(0x1_0000 - (address as usize & 0xffff));
+            if upper != Some(address >> 16) {
+                upper = Some(address >> 16);
+                record(out, 0x04, 0, &((address >> 16) as u16).to_be_bytes())?;
+            }
+            record(
+                out,
+                0x00,
+                address as u16,
+                &region.code[offset..offset + len],
+            )?;
+            offset += len;
+        }
+    }
+    record(out, 0x05, 0, &entry.to_be_bytes())?;
+    record(out, 0x01, 0, &[])
+}
+
+/// Splits a record into its type, address and data, checking the checksum.
+fn parse_record(line: &str) -> Result<(u8, u32, Vec<u8>)> {
+    let hex = line.strip_prefix(':').context("Missing ':'")?;
+    let bytes = parse_hex_bytes(hex)?;
+    ensure!(
+        bytes.len() >= 5 && bytes.len() == 5 + bytes[0] as usize,
+        "Bad record length"
+    );
+    ensure!(
+        bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0,
+        "Bad checksum"
+    );
+    let address = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+    Ok((bytes[3], address, bytes[4..bytes.len() - 1].to_vec()))
+}
+
+pub fn read(text: &str) -> Result<Vec<Region>> {
+    let mut regions = vec![];
+    let mut base = 0u32;
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let context = || format!("Bad Intel HEX record on line {}", number + 1);
+        let (kind, address, data) = parse_record(line).with_context(context)?;
+        match (kind, data.as_slice()) {
+            (0x00, _) => push_bytes(&mut regions, base.wrapping_add(address), &data),
+            (0x01, _) => break,
+            (0x02, [high, low]) => base = (u16::from_be_bytes([*high, *low]) as u32) << 4,
+            (0x04, [high, low]) => base = (u16::from_be_bytes([*high, *low]) as u32) << 16,
+            (0x03 | 0x05, [_, _, _, _]) => {}
+            _ => {
+                return Err(
+                    anyhow::anyhow!("Unsupported record type {:#04x}", kind).context(context())
+                )
+            }
+        }
+    }
+    Ok(regions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ihex_roundtrip() {
+        let regions = vec![
+            Region {
+                address: 0x1fff8,
+                code: (0..20).collect(),
+            },
+            Region {
+                address: 0x40000,
+                code: vec![0xde, 0xad, 0xbe, 0xef],
+            },
+        ];
+        let mut out = vec![];
+        write(&mut out, &regions, 0x1fff8).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.starts_with(":020000040001F9\n:08FFF8000001020304050607E5\n"));
+        assert!(text.ends_with(":040000050001FFF8FF\n:00000001FF\n"));
+        assert_eq!(read(&text).unwrap(), regions);
+
+        assert!(read(":040000050001FFF8FE\n").is_err());
+        assert!(read("040000050001FFF8FF\n").is_err());
+    }
+}
@@ -0,0 +1,408 @@
+use std::{collections::BTreeMap, fmt, io::Write, str::FromStr};
+
+use anyhow::{bail, ensure, Result};
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+
+use crate::{assembler::Region, source_map::SourceLocation, utils::format_parameters};
+
+pub mod elf;
+pub mod ihex;
+pub mod srec;
+
+/// The code assembled for one combination of template parameters.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Shellcode {
+    #[serde_as(as = "serde_with::hex::Hex")]
+    pub code: Vec<u8>,
+    pub parameters: BTreeMap<String, u64>,
+    pub labels: BTreeMap<String, u32>,
+    /// The regions `code` is made of, if `.org` moved any of them away.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub regions: Vec<Region>,
+    /// The source line each address was assembled from, if requested.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_map: Option<Vec<SourceLocation>>,
+}
+
+impl Shellcode {
+    pub fn new(
+        base_addr: u32,
+        parameters: BTreeMap<String, u64>,
+        regions: Vec<Region>,
+        labels: BTreeMap<String, u32>,
+    ) -> Result<Self> {
+        let code = crate::assembler::flatten(base_addr, &regions)?;
+        let regions = match regions.as_slice() {
+            [] => vec![],
+            [region] if region.address == base_addr => vec![],
+            _ => regions,
+        };
+        Ok(Self {
+            code,
+            parameters,
+            labels,
+            regions,
+            source_map: None,
+        })
+    }
+
+    /// The regions of the code when assembled at `base_addr`.
+    pub fn layout(&self, base_addr: u32) -> Vec<Region> {
+        match (self.regions.is_empty(), self.code.is_empty()) {
+            (false, _) => self.regions.clone(),
+            (true, true) => vec![],
+            (true, false) => vec![Region {
+                address: base_addr,
+                code: self.code.clone(),
+            }],
+        }
+    }
+}
+
+/// The file formats assembled code can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// The code as is. Only possible for a single parameter combination.
+    Raw,
+    /// Addresses followed by four instruction words per line.
+    Hex,
+    /// One JSON encoded `Shellcode` per line.
+    Json,
+    /// A C `unsigned char` array per parameter combination.
+    C,
+    /// A Rust `[u8; N]` constant per parameter combination.
+    Rust,
+    /// Intel HEX records. Only possible for a single parameter combination.
+    Ihex,
+    /// Motorola S-records. Only possible for a single parameter combination.
+    Srec,
+    /// A 32-bit ELF executable. Only possible for a single parameter combination.
+    Elf32,
+    /// A 64-bit ELF executable. Only possible for a single parameter combination.
+    Elf64,
+}
+
+/// How to write code, besides the format.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Options {
+    /// The address the code was assembled at.
+    pub base_addr: u32,
+    /// The array name in C and Rust output.
+    pub name: String,
+    /// The `e_machine` of ELF output.
+    pub elf_machine: u16,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            base_addr: 0,
+            name: "shellcode".to_string(),
+            elf_machine: elf::DEFAULT_MACHINE,
+        }
+    }
+}
+
+impl Format {
+    pub const ALL: &'static [Format] = &[
+        Format::Raw,
+        Format::Hex,
+        Format::Json,
+        Format::C,
+        Format::Rust,
+        Format::Ihex,
+        Format::Srec,
+        Format::Elf32,
+        Format::Elf64,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Raw => "raw",
+            Format::Hex => "hex",
+            Format::Json => "json",
+            Format::C => "c",
+            Format::Rust => "rust",
+            Format::Ihex => "ihex",
+            Format::Srec => "srec",
+            Format::Elf32 => "elf32",
+            Format::Elf64 => "elf64",
+        }
+    }
+
+    /// Whether the format holds a single image rather than one per parameter combination.
+    pub fn is_image(self) -> bool {
+        matches!(
+            self,
+            Format::Raw | Format::Ihex | Format::Srec | Format::Elf32 | Format::Elf64
+        )
+    }
+
+    pub fn write(
+        self,
+        out: &mut dyn Write,
+        options: &Options,
+        shellcodes: &[Shellcode],
+    ) -> Result<()> {
+        let base_addr = options.base_addr;
+        if self.is_image() {
+            let [shellcode] = shellcodes else {
+                bail!(
+                    "{} output needs exactly one parameter combination, got {}",
+                    self,
+                    shellcodes.len()
+                );
+            };
+            let regions = shellcode.layout(base_addr);
+            match self {
+                Format::Ihex => ihex::write(out, &regions, base_addr)?,
+                Format::Srec => srec::write(out, &regions, base_addr)?,
+                Format::Elf32 | Format::Elf64 => out.write_all(&elf::write(
+                    self == Format::Elf64,
+                    options.elf_machine,
+                    base_addr,
+                    &regions,
+                    &shellcode.labels,
+                ))?,
+                _ => out.write_all(&shellcode.code)?,
+            }
+            return Ok(());
+        }
+
+        for (index, shellcode) in shellcodes.iter().enumerate() {
+            let name = match shellcodes.len() {
+                1 => options.name.clone(),
+                _ => format!("{}_{}", options.name, index),
+            };
+            match self {
+                Format::Raw | Format::Ihex | Format::Srec | Format::Elf32 | Format::Elf64 => {
+                    unreachable!()
+                }
+                Format::Hex => write_hex(out, base_addr, shellcode)?,
+                Format::Json => writeln!(out, "{}", serde_json::to_string(shellcode)?)?,
+                Format::C => write_array(
+                    out,
+                    shellcode,
+                    &format!(
+                        "const unsigned char {}[{}] = {{",
+                        name,
+                        shellcode.code.len()
+                    ),
+                    "};",
+                )?,
+                Format::Rust => write_array(
+                    out,
+                    shellcode,
+                    &format!(
+                        "pub const {}: [u8; {}] = [",
+                        name.to_uppercase(),
+                        shellcode.code.len()
+                    ),
+                    "];",
+                )?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads back code written in this format, placing raw code at `base_addr`.
+    pub fn read(self, data: &[u8], base_addr: u32) -> Result<Vec<Region>> {
+        match self {
+            Format::Raw => Ok(vec![Region {
+                address: base_addr,
+                code: data.to_vec(),
+            }]),
+            Format::Ihex => ihex::read(std::str::from_utf8(data)?),
+            Format::Srec => srec::read(std::str::from_utf8(data)?),
+            Format::Elf32 | Format::Elf64 => Ok(elf::read(data)?.0),
+            _ => bail!("Reading {} files is not supported", self),
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let format = Format::ALL.iter().find(|format| format.name() == s);
+        ensure!(
+            format.is_some(),
+            "Unknown format {}, expected one of {}",
+            s,
+            Format::ALL
+                .iter()
+                .map(|format| format.name())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        Ok(*format.unwrap())
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+fn write_hex(out: &mut dyn Write, base_addr: u32, shellcode: &Shellcode) -> Result<()> {
+    if !shellcode.parameters.is_empty() {
+        writeln!(out, "# {}", format_parameters(&shellcode.parameters))?;
+    }
+    for region in shellcode.layout(base_addr) {
+        for (index, line) in region.code.chunks(16).enumerate() {
+            write!(out, "{:08x}:", region.address as usize + 16 * index)?;
+            for word in line.chunks(4) {
+                write!(out, " ")?;
+                for byte in word {
+                    write!(out, "{:02x}", byte)?;
+                }
+            }
+            writeln!(out)?;
+        }
+    }
+    Ok(())
+}
+
+/// Appends `data` at `address`, extending the last region if it ends there.
+fn push_bytes(regions: &mut Vec<Region>, address: u32, data: &[u8]) {
+    match regions.last_mut() {
+        Some(region) if region.end() == address as u64 => region.code.extend_from_slice(data),
+        _ => regions.push(Region {
+            address,
+            code: data.to_vec(),
+        }),
+    }
+}
+
+fn write_array(out: &mut dyn Write, shellcode: &Shellcode, open: &str, close: &str) -> Result<()> {
+    if !shellcode.parameters.is_empty() {
+        writeln!(out, "// {}", format_parameters(&shellcode.parameters))?;
+    }
+    writeln!(out, "{}", open)?;
+    for line in shellcode.code.chunks(8) {
+        let bytes = line
+            .iter()
+            .map(|byte| format!("0x{:02x},", byte))
+            .collect::<Vec<_>>();
+        writeln!(out, "    {}", bytes.join(" "))?;
+    }
+    writeln!(out, "{}", close)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shellcode(code: &[u8], parameters: &[(&str, u64)]) -> Shellcode {
+        Shellcode {
+            code: code.to_vec(),
+            parameters: parameters
+                .iter()
+                .map(|(key, value)| (key.to_string(), *value))
+                .collect(),
+            labels: BTreeMap::new(),
+            regions: vec![],
+            source_map: None,
+        }
+    }
+
+    fn options(base_addr: u32) -> Options {
+        Options {
+            base_addr,
+            name: "code".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn write(format: Format, shellcodes: &[Shellcode]) -> String {
+        let mut out = vec![];
+        format
+            .write(&mut out, &options(0x1000), shellcodes)
+            .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn format_parse() {
+        for format in Format::ALL {
+            assert_eq!(format.to_string().parse::<Format>().unwrap(), *format);
+        }
+        assert!("elf".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn format_write() {
+        let code = (0..20).collect::<Vec<u8>>();
+        let single = [shellcode(&code, &[])];
+
+        let mut out = vec![];
+        Format::Raw.write(&mut out, &options(0), &single).unwrap();
+        assert_eq!(out, code);
+
+        assert_eq!(
+            write(Format::Hex, &single),
+            "00001000: 00010203 04050607 08090a0b 0c0d0e0f\n00001010: 10111213\n"
+        );
+        assert_eq!(
+            write(Format::Json, &single),
+            format!("{}\n", serde_json::to_string(&single[0]).unwrap())
+        );
+        assert!(write(Format::C, &single).starts_with(
+            "const unsigned char code[20] = {\n    0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07,\n"
+        ));
+        assert!(write(Format::Rust, &single).ends_with("    0x10, 0x11, 0x12, 0x13,\n];\n"));
+
+        for format in Format::ALL.iter().filter(|format| format.is_image()) {
+            let mut out = vec![];
+            format.write(&mut out, &options(0x1000), &single).unwrap();
+            let regions = format.read(&out, 0x1000).unwrap();
+            assert_eq!(regions, single[0].layout(0x1000), "{}", format);
+        }
+    }
+
+    #[test]
+    fn format_write_regions() {
+        let regions = vec![
+            Region {
+                address: 0x1000,
+                code: vec![0, 1, 2, 3],
+            },
+            Region {
+                address: 0x1010,
+                code: vec![4, 5, 6, 7],
+            },
+        ];
+        let shellcodes =
+            [Shellcode::new(0x1000, BTreeMap::new(), regions.clone(), BTreeMap::new()).unwrap()];
+        assert_eq!(shellcodes[0].code.len(), 0x14);
+        assert_eq!(
+            write(Format::Hex, &shellcodes),
+            "00001000: 00010203\n00001010: 04050607\n"
+        );
+        let mut out = vec![];
+        Format::Ihex
+            .write(&mut out, &options(0x1000), &shellcodes)
+            .unwrap();
+        assert_eq!(Format::Ihex.read(&out, 0).unwrap(), regions);
+    }
+
+    #[test]
+    fn format_write_multiple() {
+        let multiple = [
+            shellcode(&[0, 1, 2, 3], &[("x", 1)]),
+            shellcode(&[4, 5, 6, 7], &[("x", 2)]),
+        ];
+        assert!(Format::Raw
+            .write(&mut vec![], &options(0), &multiple)
+            .is_err());
+        assert_eq!(
+            write(Format::Rust, &multiple),
+            "// x = 0x1\npub const CODE_0: [u8; 4] = [\n    0x00, 0x01, 0x02, 0x03,\n];\n\
+             // x = 0x2\npub const CODE_1: [u8; 4] = [\n    0x04, 0x05, 0x06, 0x07,\n];\n"
+        );
+    }
+}
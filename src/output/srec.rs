@@ -0,0 +1,116 @@
+//! Motorola S-records, using S3 data records for 32-bit addresses.
+
+use std::io::Write;
+
+use anyhow::{bail, ensure, Context, Result};
+
+use super::push_bytes;
+use crate::assembler::Region;
+use crate::utils::parse_hex_bytes;
+
+fn record(out: &mut dyn Write, kind: u8, address: &[u8], data: &[u8]) -> Result<()> {
+    let mut bytes = vec![(address.len() + data.len() + 1) as u8];
+    bytes.extend_from_slice(address);
+    bytes.extend_from_slice(data);
+    let checksum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    bytes.push(!checksum);
+
+    write!(out, "S{}", kind)?;
+    for byte in bytes {
+        write!(out, "{:02X}", byte)?;
+    }
+    writeln!(out)?;
+    Ok(())
+}
+
+/// Writes `regions` as data records, followed by `entry` as the start address.
+pub fn write(out: &mut dyn Write, regions: &[Region], entry: u32) -> Result<()> {
+    record(out, 0, &[0, 0], b"irisc-asm")?;
+    for region in regions {
+        for (index, chunk) in region.code.chunks(16).enumerate() {
+            let address = region.address + 16 * index as u32;
+            record(out, 3, &address.to_be_bytes(), chunk)?;
+        }
+    }
+    record(out, 7, &entry.to_be_bytes(), &[])
+}
+
+/// Splits a record into its type, address and data, checking the checksum.
+fn parse_record(line: &str) -> Result<(u8, u32, Vec<u8>)> {
+    let hex = line.strip_prefix('S').context("Missing 'S'")?;
+    let (kind, hex) = hex.split_at_checked(1).context("Missing record type")?;
+    let kind = kind.as_bytes()[0];
+    let bytes = parse_hex_bytes(hex)?;
+    ensure!(
+        !bytes.is_empty() && bytes.len() == 1 + bytes[0] as usize,
+        "Bad record length"
+    );
+    ensure!(
+        bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0xff,
+        "Bad checksum"
+    );
+    let address_len = match kind {
+        b'0' | b'1' | b'5' | b'9' => 2,
+        b'2' | b'6' | b'8' => 3,
+        b'3' | b'7' => 4,
+        _ => bail!("Unsupported record type S{}", kind as char),
+    };
+    ensure!(bytes.len() >= address_len + 2, "Bad record length");
+    let address = bytes[1..1 + address_len]
+        .iter()
+        .fold(0u32, |address, byte| address << 8 | *byte as u32);
+    Ok((
+        kind,
+        address,
+        bytes[1 + address_len..bytes.len() - 1].to_vec(),
+    ))
+}
+
+pub fn read(text: &str) -> Result<Vec<Region>> {
+    let mut regions = vec![];
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (kind, address, data) =
+            parse_record(line).with_context(|| format!("Bad S-record on line {}", number + 1))?;
+        match kind {
+            b'1' | b'2' | b'3' => push_bytes(&mut regions, address, &data),
+            b'7' | b'8' | b'9' => break,
+            _ => {}
+        }
+    }
+    Ok(regions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn srec_roundtrip() {
+        let regions = vec![
+            Region {
+                address: 0x1000,
+                code: (0..20).collect(),
+            },
+            Region {
+                address: 0x8000_0000,
+                code: vec![0xde, 0xad, 0xbe, 0xef],
+            },
+        ];
+        let mut out = vec![];
+        write(&mut out, &regions, 0x1000).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.starts_with("S00C000069726973632D61736D6B\n"));
+        assert!(text.contains("\nS30980000000DEADBEEF3E\n"));
+        assert!(text.ends_with("S70500001000EA\n"));
+        assert_eq!(read(&text).unwrap(), regions);
+
+        assert!(read("S70500001000EB\n").is_err());
+        assert!(read("S1030000FC\nSX0500001000EA\n").is_err());
+        assert!(read("S\u{e9}0500001000EA\n").is_err());
+        assert!(read("S\n").is_err());
+    }
+}
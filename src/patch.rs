@@ -0,0 +1,254 @@
+//! Patching code into an existing firmware image.
+
+use anyhow::{bail, ensure, Context, Result};
+
+use crate::{
+    assembler::Region, extensions::Extensions, instructions::Instruction, utils::parse_address,
+};
+
+/// A firmware image and the address it is loaded at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Firmware {
+    pub load_addr: u32,
+    pub image: Vec<u8>,
+}
+
+impl Firmware {
+    fn end(&self) -> u64 {
+        self.load_addr as u64 + self.image.len() as u64
+    }
+
+    fn range(&self, address: u32, len: usize) -> Result<std::ops::Range<usize>> {
+        let end = address as u64 + len as u64;
+        ensure!(
+            self.load_addr <= address && end <= self.end(),
+            "{:#x}..{:#x} is outside the firmware image at {:#x}..{:#x}",
+            address,
+            end,
+            self.load_addr,
+            self.end()
+        );
+        let start = (address - self.load_addr) as usize;
+        Ok(start..start + len)
+    }
+
+    fn bytes_mut(&mut self, address: u32, len: usize) -> Result<&mut [u8]> {
+        let range = self.range(address, len)?;
+        Ok(&mut self.image[range])
+    }
+
+    /// Reads the big-endian word at `address`.
+    pub fn word(&self, address: u32) -> Result<u32> {
+        let range = self.range(address, 4)?;
+        Ok(u32::from_be_bytes(self.image[range].try_into().unwrap()))
+    }
+
+    /// Expands the `.hook address` ... `.endhook` blocks of `source`.
+    ///
+    /// The code in a block runs in place of the instruction at `address`: that
+    /// instruction is replaced by a `jump` to the block, and the block ends with
+    /// the displaced instruction, reassembled so that PC-relative targets still
+    /// point where they did, followed by a `jump` back to `address + 4`.
+    ///
+    /// Returns the expanded source and the hooked words, which the patch may
+    /// write to besides its declared regions.
+    pub fn expand_hooks(
+        &self,
+        source: &str,
+        extensions: &Extensions,
+    ) -> Result<(String, Vec<(u32, u64)>)> {
+        let mut output = vec![];
+        let mut hooks: Vec<u32> = vec![];
+        let mut open = None;
+        for line in source.lines() {
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next(), words.next()) {
+                (Some(".hook"), Some(address), None) => {
+                    if let Some(open) = open {
+                        bail!(".hook {:#x} is missing its .endhook", open);
+                    }
+                    let address = parse_address(address)?;
+                    ensure!(
+                        address.is_multiple_of(4),
+                        ".hook {:#x} is not word aligned",
+                        address
+                    );
+                    ensure!(
+                        !hooks.contains(&address),
+                        ".hook {:#x} is hooked twice",
+                        address
+                    );
+                    hooks.push(address);
+                    open = Some(address);
+                    output.push(format!("lbl {}", hook_label(address)));
+                }
+                (Some(".endhook"), None, None) => {
+                    let address = open.take().context(".endhook without .hook")?;
+                    let word = self.word(address)?;
+                    output.push(Instruction::decode_with(word, address, extensions).to_string());
+                    output.push(format!("jump {:#x}", address.wrapping_add(4)));
+                }
+                _ => output.push(line.to_string()),
+            }
+        }
+        if let Some(open) = open {
+            bail!(".hook {:#x} is missing its .endhook", open);
+        }
+        for address in hooks.iter() {
+            output.push(format!(".org {:#x}", address));
+            output.push(format!("jump {}", hook_label(*address)));
+        }
+        output.push(String::new());
+        let sites = hooks
+            .into_iter()
+            .map(|address| (address, address as u64 + 4))
+            .collect();
+        Ok((output.join("\n"), sites))
+    }
+
+    /// Checks that the image holds `expected` before it is patched.
+    pub fn verify(&self, expected: &Region) -> Result<()> {
+        let found = &self.image[self.range(expected.address, expected.code.len())?];
+        ensure!(
+            found == expected.code.as_slice(),
+            "Expected {} at {:#x}, found {}",
+            hex(&expected.code),
+            expected.address,
+            hex(found)
+        );
+        Ok(())
+    }
+
+    /// Writes `code` into the image, which must lie within one of `allowed`.
+    ///
+    /// Every region is checked before anything is written, so a refused patch
+    /// leaves the image untouched.
+    pub fn patch(&mut self, code: &[Region], allowed: &[(u32, u64)]) -> Result<()> {
+        for region in code {
+            ensure!(
+                allowed
+                    .iter()
+                    .any(|(start, end)| *start <= region.address && region.end() <= *end),
+                "Code at {:#x}..{:#x} is outside the declared regions",
+                region.address,
+                region.end()
+            );
+            self.bytes_mut(region.address, region.code.len())?;
+        }
+        for region in code {
+            self.bytes_mut(region.address, region.code.len())?
+                .copy_from_slice(&region.code);
+        }
+        Ok(())
+    }
+}
+
+fn hook_label(address: u32) -> String {
+    format!("__hook_{:x}", address)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image() -> Firmware {
+        Firmware {
+            load_addr: 0x1000,
+            image: (0..32).collect(),
+        }
+    }
+
+    #[test]
+    fn patch_regions() {
+        let (code, _) = crate::assemble_regions_with(
+            &Default::default(),
+            0x1000,
+            ".org 0x1008\ndword 0xaabbccdd\n.org 0x1018\ndword 0x11223344\n",
+        )
+        .unwrap();
+        let allowed = [(0x1008, 0x100c), (0x1010, 0x1020)];
+
+        let mut firmware = image();
+        firmware.patch(&code, &allowed).unwrap();
+        assert_eq!(
+            &firmware.image[4..16],
+            &[4, 5, 6, 7, 0xaa, 0xbb, 0xcc, 0xdd, 12, 13, 14, 15]
+        );
+        assert_eq!(&firmware.image[0x18..0x1c], &[0x11, 0x22, 0x33, 0x44]);
+
+        let mut firmware = image();
+        let err = firmware.patch(&code, &allowed[1..]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Code at 0x1008..0x100c is outside the declared regions"
+        );
+        assert_eq!(firmware, image());
+
+        let outside = [Region {
+            address: 0x101c,
+            code: vec![0; 8],
+        }];
+        assert!(firmware.patch(&outside, &[(0, 1 << 32)]).is_err());
+    }
+
+    #[test]
+    fn hook_relocates() {
+        let extensions = Extensions::default();
+        let (original, _) = crate::assemble(0x1000, "addi r1, r0, 1\ncall 0x1100\n").unwrap();
+        let mut firmware = Firmware {
+            load_addr: 0x1000,
+            image: [original, vec![0; 0x20]].concat(),
+        };
+        let source = ".org 0x1010\n.hook 0x1004\naddi r2, r0, 2\n.endhook\n";
+        let (source, sites) = firmware.expand_hooks(source, &extensions).unwrap();
+        assert_eq!(sites, [(0x1004, 0x1008)]);
+
+        let (code, _) = crate::assemble_regions_with(&extensions, 0x1000, &source).unwrap();
+        firmware
+            .patch(&code, &[(0x1010, 0x1020), (0x1004, 0x1008)])
+            .unwrap();
+        let region = Region {
+            address: 0x1000,
+            code: firmware.image.clone(),
+        };
+        let disassembly = region
+            .disassemble(&extensions)
+            .map(|(_, _, instruction)| instruction.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(disassembly[1], "jump 0x1010");
+        assert_eq!(
+            disassembly[4..7],
+            ["addi r2, r0, 0x2", "call 0x1100", "jump 0x1008"]
+        );
+
+        assert!(firmware
+            .expand_hooks(".hook 0x1004\n", &extensions)
+            .is_err());
+        assert!(firmware.expand_hooks(".endhook\n", &extensions).is_err());
+        assert!(firmware
+            .expand_hooks(".hook 0x2000\n.endhook\n", &extensions)
+            .is_err());
+    }
+
+    #[test]
+    fn patch_verify() {
+        let firmware = image();
+        let expected = Region {
+            address: 0x1004,
+            code: vec![4, 5, 6, 7],
+        };
+        firmware.verify(&expected).unwrap();
+        let expected = Region {
+            address: 0x1004,
+            code: vec![4, 5, 6, 8],
+        };
+        assert_eq!(
+            firmware.verify(&expected).unwrap_err().to_string(),
+            "Expected 04050608 at 0x1004, found 04050607"
+        );
+    }
+}
@@ -0,0 +1,84 @@
+//! Checking whether code can run at any address.
+
+use anyhow::Context;
+
+use crate::{
+    extensions::Extensions,
+    listing::{listing_with, Listing, ListingLine},
+};
+
+/// How far the code is moved to find the lines that depend on where it is.
+///
+/// Moving by more than 64K changes the upper halfword of addresses, and the
+/// extra word changes the lower one.
+const SHIFT: u32 = 0x1_0004;
+
+/// The lines of `source`, listed in `listing` as assembled at `base_addr`,
+/// whose words change when it is assembled somewhere else, along with the
+/// `.org` lines that pin code to an absolute address.
+///
+/// Relative `jump`s and `call`s to labels stay the same; absolute `%hwN`
+/// operands, `.word` addresses and relative references to fixed addresses
+/// do not.
+pub fn position_dependent(
+    extensions: &Extensions,
+    base_addr: u32,
+    source: &str,
+    listing: &Listing,
+) -> anyhow::Result<Vec<ListingLine>> {
+    // Move down instead when moving up wraps around or runs into a `.org`.
+    let mut shifted = Err(anyhow::anyhow!("No address to move the code to"));
+    for shifted_addr in [base_addr.checked_add(SHIFT), base_addr.checked_sub(SHIFT)]
+        .into_iter()
+        .flatten()
+    {
+        shifted = listing_with(extensions, shifted_addr, source)
+            .with_context(|| format!("Failed to assemble at {:#x} as well", shifted_addr));
+        if shifted.is_ok() {
+            break;
+        }
+    }
+    let shifted = shifted?;
+
+    Ok(listing
+        .lines
+        .iter()
+        .zip(shifted.lines)
+        .filter(|(line, shifted)| {
+            line.words != shifted.words || line.text.trim().starts_with(".org")
+        })
+        .map(|(line, _)| line.clone())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(base_addr: u32, source: &str) -> Vec<ListingLine> {
+        let listing = listing_with(&Extensions::default(), base_addr, source).unwrap();
+        position_dependent(&Extensions::default(), base_addr, source, &listing).unwrap()
+    }
+
+    #[test]
+    fn pic_lines() {
+        let source = "lbl start\njump start\nset2 r1, r0, %hw2(start)\nset3 r1, r1, %hw3(start)\n\
+                      .word start\ncall 0x2000\naddi r1, r0, 1\n";
+        let lines = check(0x1000, source);
+        assert_eq!(
+            lines.iter().map(|line| line.line).collect::<Vec<_>>(),
+            [3, 4, 5, 6]
+        );
+
+        let lines = check(0, ".org 0x100\naddi r1, r0, 1\n");
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].text, ".org 0x100");
+
+        // Moving up would wrap around, or overlap the `.org` region.
+        let lines = check(0xffff_f000, "jump 0x0\n");
+        assert_eq!(lines.len(), 1);
+        let source = "addi r1, r0, 1\n.org 0x30004\naddi r1, r0, 1\n";
+        let lines = check(0x2_0000, source);
+        assert_eq!(lines.len(), 1);
+    }
+}
@@ -0,0 +1,153 @@
+//! Maps from code addresses back to the source lines they were assembled from.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{listing::Listing, render_template};
+
+/// Starts the comment lines marking where each template line is rendered.
+const MARKER: &str = "#@line ";
+
+/// The words of one source line.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SourceLocation {
+    pub address: u32,
+    /// The number of words the line assembled to.
+    pub words: u32,
+    /// The template the source was rendered from.
+    pub file: String,
+    /// The 1-based line number in `file`, `None` if the rendering can't be
+    /// followed back, e.g. from tags spanning lines.
+    pub line: Option<usize>,
+    /// The 1-based line number in the rendered source.
+    pub rendered_line: usize,
+    /// The rendered line.
+    pub text: String,
+}
+
+impl SourceLocation {
+    pub fn contains(&self, address: u32) -> bool {
+        self.address <= address && (address as u64) < self.address as u64 + 4 * self.words as u64
+    }
+}
+
+/// The source locations of the code assembled for one parameter combination.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SourceMap {
+    pub parameters: BTreeMap<String, u64>,
+    pub locations: Vec<SourceLocation>,
+}
+
+impl SourceMap {
+    /// Locates the lines of `template`, read from `file`, that emitted words
+    /// in `listing`, the listing of `template` rendered with `parameters`.
+    pub fn new(
+        file: &str,
+        template: &str,
+        parameters: BTreeMap<String, u64>,
+        listing: &Listing,
+    ) -> Self {
+        let template_lines = template_lines(template, &parameters, listing);
+        let locations = listing
+            .lines
+            .iter()
+            .filter(|line| !line.words.is_empty())
+            .map(|line| SourceLocation {
+                address: line.address.unwrap(),
+                words: line.words.len() as u32,
+                file: file.to_string(),
+                line: template_lines.get(line.line - 1).copied().flatten(),
+                rendered_line: line.line,
+                text: line.text.clone(),
+            })
+            .collect();
+        Self {
+            parameters,
+            locations,
+        }
+    }
+
+    pub fn find(&self, address: u32) -> Option<&SourceLocation> {
+        self.locations
+            .iter()
+            .find(|location| location.contains(address))
+    }
+}
+
+/// The template line each line of `listing` was rendered from.
+///
+/// Renders `template` again with a comment line marking the start of every
+/// template line, and gives up if dropping the markers doesn't give back the
+/// listed source.
+fn template_lines(
+    template: &str,
+    parameters: &BTreeMap<String, u64>,
+    listing: &Listing,
+) -> Vec<Option<usize>> {
+    let marked = template
+        .lines()
+        .enumerate()
+        .map(|(index, line)| format!("{}{}\n{}", MARKER, index + 1, line))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let rendered = render_template(&marked, parameters).unwrap_or_default();
+    let mut current = None;
+    let mut lines = vec![];
+    let mut texts = vec![];
+    for line in rendered.lines() {
+        match line.strip_prefix(MARKER) {
+            Some(number) => current = number.parse().ok(),
+            None => {
+                lines.push(current);
+                texts.push(line);
+            }
+        }
+    }
+    if texts.iter().ne(listing.lines.iter().map(|line| &line.text)) {
+        return vec![None; listing.lines.len()];
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn source_map_find() {
+        let source = "lbl start\n\nset32 r1, 0x1234\n.org 0x2000\njump start\n";
+        let listing = crate::listing::listing(0x1000, source).unwrap();
+        let map = SourceMap::new("test.asm", source, BTreeMap::new(), &listing);
+        assert_eq!(map.locations.len(), 2);
+        assert_eq!(map.find(0x1004).unwrap().line, Some(3));
+        assert_eq!(map.find(0x1004).unwrap().text, "set32 r1, 0x1234");
+        assert_eq!(map.find(0x2000).unwrap().line, Some(5));
+        assert_eq!(map.find(0x1008), None);
+        assert_eq!(map.find(0xffc), None);
+    }
+
+    #[test]
+    fn source_map_template_lines() {
+        let template =
+            "lbl start\n{% for i in range(end=n) %}\naddi r1, r1, {{ i }}\n{% endfor %}\n\
+                        jump start\n";
+        let parameters = BTreeMap::from([("n".to_string(), 2)]);
+        let source = render_template(template, &parameters).unwrap();
+        let listing = crate::listing::listing(0, &source).unwrap();
+        let map = SourceMap::new("loop.asm", template, parameters.clone(), &listing);
+        let lines = map
+            .locations
+            .iter()
+            .map(|location| (location.line, location.rendered_line))
+            .collect::<Vec<_>>();
+        assert_eq!(lines, [(Some(3), 3), (Some(3), 5), (Some(5), 7)]);
+
+        // A tag spanning lines can't take the markers.
+        let template = "lbl start\n{% if n\n %}addi r1, r1, 1{% endif %}\n";
+        let source = render_template(template, &parameters).unwrap();
+        let listing = crate::listing::listing(0, &source).unwrap();
+        let map = SourceMap::new("if.asm", template, parameters, &listing);
+        assert_eq!(map.locations[0].line, None);
+    }
+}
@@ -1,4 +1,8 @@
-use anyhow::{bail, Context, Result};
+use std::{collections::BTreeMap, path::PathBuf};
+
+use anyhow::{bail, ensure, Context, Result};
+
+use crate::fields::Reg;
 
 // parse everything from -2**63-1 to 2**64-1 into a u64
 pub fn parse_number(number: &str) -> Result<u64> {
@@ -56,6 +60,68 @@ pub fn parse_parameter(s: &str) -> Result<(String, Vec<u64>)> {
     ))
 }
 
+pub fn parse_address(s: &str) -> Result<u32> {
+    let number = parse_number(s).context(format!("Invalid number: {}", s))?;
+    u32::try_from(number).context(format!("Address out of range: {}", s))
+}
+
+pub fn parse_byte(s: &str) -> Result<u8> {
+    let number = parse_number(s).context(format!("Invalid number: {}", s))?;
+    u8::try_from(number).context(format!("Byte out of range: {}", s))
+}
+
+/// Parses `start..end` into an address range, `end` being exclusive.
+pub fn parse_address_range(s: &str) -> Result<(u32, u64)> {
+    let (start, end) = s.split_once("..").context("no '..' in range")?;
+    let (start, end) = (parse_address(start)?, parse_number(end)?);
+    if end < start as u64 || end > 1 << 32 {
+        bail!("Invalid range: {}", s);
+    }
+    Ok((start, end))
+}
+
+/// Parses `address=hexbytes`.
+pub fn parse_address_bytes(s: &str) -> Result<(u32, Vec<u8>)> {
+    let (address, hex) = s.split_once('=').context("no '=' in argument")?;
+    let bytes = parse_hex_bytes(hex).with_context(|| format!("Invalid hex: {}", hex))?;
+    Ok((parse_address(address)?, bytes))
+}
+
+/// Parses pairs of hex digits without a prefix.
+pub fn parse_hex_bytes(hex: &str) -> Result<Vec<u8>> {
+    ensure!(
+        hex.len().is_multiple_of(2) && hex.is_ascii(),
+        "Odd number of hex digits"
+    );
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&hex[i..i + 2], 16)?))
+        .collect()
+}
+
+/// Parses `path@address`.
+pub fn parse_path_address(s: &str) -> Result<(PathBuf, u32)> {
+    let (path, address) = s.rsplit_once('@').context("no '@' in argument")?;
+    Ok((PathBuf::from(path), parse_address(address)?))
+}
+
+/// Parses `register=value`.
+pub fn parse_register_value(s: &str) -> Result<(Reg, u64)> {
+    let (reg, value) = s.split_once('=').context("no '=' in argument")?;
+    let reg = reg.parse().context(format!("Invalid register: {}", reg))?;
+    let value = parse_number(value).context(format!("Invalid number: {}", value))?;
+    Ok((reg, value))
+}
+
+/// Describes a parameter combination as `key = value, ...`.
+pub fn format_parameters(parameters: &BTreeMap<String, u64>) -> String {
+    parameters
+        .iter()
+        .map(|(key, value)| format!("{} = {:#x}", key, value))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
 pub fn cartesian_product<K: Clone, V: Clone>(sets: Vec<(K, Vec<V>)>) -> Vec<Vec<(K, V)>> {
     if let Some(((k, set), rest)) = sets.split_first() {
         set.iter()
@@ -105,6 +171,26 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_parse_address() {
+        assert_eq!(parse_address("0xffffffff").unwrap(), 0xffffffff);
+        assert!(parse_address("0x100000000").is_err());
+        assert_eq!(
+            parse_address_range("0x100..0x100000000").unwrap(),
+            (0x100, 0x1_0000_0000)
+        );
+        assert!(parse_address_range("0x100..0x80").is_err());
+        assert_eq!(
+            parse_address_bytes("16=dead").unwrap(),
+            (16, vec![0xde, 0xad])
+        );
+        assert!(parse_address_bytes("16=dea").is_err());
+        assert_eq!(
+            parse_path_address("fw@1.bin@0x8000").unwrap(),
+            (PathBuf::from("fw@1.bin"), 0x8000)
+        );
+    }
+
     #[test]
     fn test_parse_ranges() {
         assert_eq!(parse_ranges("1,2,3").unwrap(), vec![1, 2, 3]);
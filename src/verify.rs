@@ -0,0 +1,192 @@
+//! Comparing emulated runs with results recorded on hardware.
+
+use std::{collections::BTreeMap, fmt};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+
+use crate::{
+    assembler::Region,
+    emu::{Emulator, Stop},
+    output::Shellcode,
+    utils::format_parameters,
+};
+
+/// The output buffer one parameter combination left behind on hardware.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Observation {
+    pub parameters: BTreeMap<String, u64>,
+    #[serde_as(as = "serde_with::hex::Hex")]
+    pub output: Vec<u8>,
+}
+
+/// Reads one JSON value per non-empty line.
+pub fn read_jsonl<T: serde::de::DeserializeOwned>(text: &str) -> anyhow::Result<Vec<T>> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            serde_json::from_str(line).with_context(|| format!("Bad JSON on line {}", index + 1))
+        })
+        .collect()
+}
+
+/// Builds the emulator for one run with `regions` loaded, e.g. with the
+/// buffer pointer in a register.
+pub type NewEmulator = dyn Fn(&[Region]) -> anyhow::Result<Emulator>;
+
+/// How each program is run.
+pub struct Setup {
+    pub base_addr: u32,
+    /// The output buffer, `start..end`.
+    pub buffer: (u32, u64),
+    pub max_steps: u64,
+    pub emulator: Box<NewEmulator>,
+}
+
+impl Setup {
+    /// Runs `shellcode`, returning its output buffer and why it stopped.
+    pub fn run(&self, shellcode: &Shellcode) -> anyhow::Result<(Vec<u8>, Stop)> {
+        let mut emulator = (self.emulator)(&shellcode.layout(self.base_addr))?;
+        let stop = emulator.run(self.max_steps);
+        let (start, end) = self.buffer;
+        let output = (start as u64..end)
+            .map(|address| emulator.peek(address, 1) as u8)
+            .collect();
+        Ok((output, stop))
+    }
+}
+
+/// A combination whose emulated run doesn't match the hardware.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    pub parameters: BTreeMap<String, u64>,
+    pub buffer_addr: u32,
+    pub emulated: Vec<u8>,
+    pub hardware: Vec<u8>,
+    pub stop: Stop,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:", format_parameters(&self.parameters))?;
+        if self.stop != Stop::Returned {
+            write!(f, "\n    emulation stopped: {}", self.stop)?;
+        }
+        if self.emulated.len() != self.hardware.len() {
+            write!(
+                f,
+                "\n    emulated {} bytes, hardware {}",
+                self.emulated.len(),
+                self.hardware.len()
+            )?;
+        }
+        let words = self.emulated.chunks(4).zip(self.hardware.chunks(4));
+        for (index, (emulated, hardware)) in words.enumerate() {
+            if emulated != hardware {
+                write!(
+                    f,
+                    "\n    {:#x}: emulated {}, hardware {}",
+                    self.buffer_addr as usize + 4 * index,
+                    hex(emulated),
+                    hex(hardware)
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The outcome of comparing every combination.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    pub matched: usize,
+    pub mismatches: Vec<Mismatch>,
+    /// Combinations without a hardware result.
+    pub missing: Vec<BTreeMap<String, u64>>,
+}
+
+/// Runs every shellcode and compares its buffer with the observation for the same parameters.
+pub fn verify(
+    setup: &Setup,
+    shellcodes: &[Shellcode],
+    observations: &[Observation],
+) -> anyhow::Result<Report> {
+    let mut report = Report::default();
+    for shellcode in shellcodes {
+        let Some(observation) = observations
+            .iter()
+            .find(|observation| observation.parameters == shellcode.parameters)
+        else {
+            report.missing.push(shellcode.parameters.clone());
+            continue;
+        };
+        let (emulated, stop) = setup.run(shellcode).with_context(|| {
+            format!("Failed to run {}", format_parameters(&shellcode.parameters))
+        })?;
+        if emulated == observation.output && stop == Stop::Returned {
+            report.matched += 1;
+        } else {
+            report.mismatches.push(Mismatch {
+                parameters: shellcode.parameters.clone(),
+                buffer_addr: setup.buffer.0,
+                emulated,
+                hardware: observation.output.clone(),
+                stop,
+            });
+        }
+    }
+    Ok(report)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{extensions::Extensions, fields::Reg};
+
+    #[test]
+    fn verify_buffers() {
+        let template = "set32 r5, {{ a }}\naddi r6, r5, 1\nst.d r0, r4, r5, 0x0\n\
+                        st.d r0, r4, r6, 0x4\nret.d\n";
+        let shellcodes = [1u64, 2, 3]
+            .into_iter()
+            .map(|a| {
+                let parameters = BTreeMap::from([("a".to_string(), a)]);
+                let source = crate::render_template(template, &parameters).unwrap();
+                let (regions, labels) =
+                    crate::assemble_regions_with(&Extensions::default(), 0, &source).unwrap();
+                Shellcode::new(0, parameters, regions, labels).unwrap()
+            })
+            .collect::<Vec<_>>();
+        let observations = read_jsonl::<Observation>(
+            "{\"parameters\": {\"a\": 1}, \"output\": \"0000000100000002\"}\n\n\
+             {\"parameters\": {\"a\": 2}, \"output\": \"0000000200000004\"}\n",
+        )
+        .unwrap();
+        let setup = Setup {
+            base_addr: 0,
+            buffer: (0x1000, 0x1008),
+            max_steps: 100,
+            emulator: Box::new(|regions| {
+                let mut emulator = Emulator::new(Extensions::default());
+                emulator.load(regions);
+                emulator.set_reg(Reg(4), 0x1000);
+                Ok(emulator)
+            }),
+        };
+
+        let report = verify(&setup, &shellcodes, &observations).unwrap();
+        assert_eq!(report.matched, 1);
+        assert_eq!(report.missing, [shellcodes[2].parameters.clone()]);
+        assert_eq!(
+            report.mismatches[0].to_string(),
+            "a = 0x2:\n    0x1004: emulated 00000003, hardware 00000004"
+        );
+    }
+}
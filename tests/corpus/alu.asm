@@ -0,0 +1,9 @@
+# register-register and immediate arithmetic
+addi r5, r0, 0x1234
+addi r6, r5, -0x4
+addi r7, r6, 32767
+add r8, r5, r6
+sub r9, r8, r5
+subs r0, r9, r8
+alu.r 0x00b, r10, r5, r6
+alu.r 0x7ff, r31, r30, r29
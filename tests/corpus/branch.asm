@@ -0,0 +1,4 @@
+lbl top
+    subs r0, r5, r6
+    b.f 0x3, r0, top
+    b.t 0x1, r0, top
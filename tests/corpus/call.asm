@@ -0,0 +1,9 @@
+lbl start
+    call forward
+    addi r5, r0, 1
+lbl backward
+    addi r6, r0, 2
+lbl forward
+    call backward
+    call start
+    call forward
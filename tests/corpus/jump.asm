@@ -0,0 +1,3 @@
+lbl loop
+    addi r5, r5, 1
+    jump loop
@@ -0,0 +1 @@
+ld.d r5, r4, r0, 0x08
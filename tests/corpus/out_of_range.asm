@@ -0,0 +1 @@
+addi r5, r0, 0x8000
@@ -0,0 +1 @@
+ret.d r0, r0, r0
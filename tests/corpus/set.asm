@@ -0,0 +1,5 @@
+set0 r5, r0, 0x1234
+set1 r5, r5, 0x5678
+set2 r5, r5, 0x9abc
+set3 r5, r5, 0xdef0
+set0 r31, r31, 0xffff
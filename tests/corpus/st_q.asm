@@ -0,0 +1 @@
+st.q r0, r4, r7, 0x18, 0x0
@@ -0,0 +1,4 @@
+lbl store
+    st.d r0, r4, r5, 0x08
+    st.d r0, r4, r6, 0x0c
+    st.d r1, r2, r3, 0x7fc
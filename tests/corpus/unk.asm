@@ -0,0 +1,3 @@
+unk.r 0x2c, r1, r2, r3, 0x7ff
+unk.r 0x00, r31, r31, r31, 0
+unk.r 0x3f, r0, r0, r0, 0x02d
@@ -0,0 +1 @@
+addi r5, zero, 1
@@ -0,0 +1,260 @@
+//! Differential test of the Rust assembler against the bundled `irisc_asm.py`.
+//!
+//! Every `tests/corpus/*.asm` file is assembled by both implementations at a
+//! few base addresses and the resulting words and labels are compared. Cases
+//! where the two disagree on purpose are listed in `ALLOWLIST`, along with what
+//! is expected to differ.
+//!
+//! Needs `python3` with `jinja2`; the test fails rather than skips without it.
+
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+use serde::Deserialize;
+
+use irisc_asm::{instructions::INSTRUCTIONS, Instruction};
+
+const BASE_ADDRS: &[u32] = &[0x0000_0000, 0x0001_0000];
+
+/// The case, the expected difference (an instruction field, `length`,
+/// `labels`, `rust error` or `python error`) and why.
+const ALLOWLIST: &[(&str, &str, &str)] = &[
+    (
+        "jump.asm",
+        "jmpop",
+        "irisc_asm.py does not shift jmpop into bits 24-25",
+    ),
+    (
+        "ret.asm",
+        "rust error",
+        "irisc_asm.py takes rd, rs, rt operands for ret.d",
+    ),
+    (
+        "st_q.asm",
+        "rust error",
+        "irisc_asm.py takes the width of st.q as an operand",
+    ),
+    (
+        "ld_d.asm",
+        "rust error",
+        "irisc_asm.py encodes ld.d like st.d with an rt operand",
+    ),
+    (
+        "zero.asm",
+        "python error",
+        "irisc_asm.py does not accept `zero` as a register",
+    ),
+];
+
+const PYTHON_DRIVER: &str = r#"
+import json, sys
+sys.path.insert(0, sys.argv[1])
+import irisc_asm
+
+source = sys.stdin.read()
+base = int(sys.argv[2])
+try:
+    ctx = irisc_asm.Context(base)
+    irisc_asm.assemble_pass(ctx, source)
+    ctx = irisc_asm.Context(base, ctx.labels)
+    irisc_asm.assemble_pass(ctx, source)
+    print(json.dumps({"code": ctx.code.hex(), "labels": ctx.labels}))
+except Exception as e:
+    print(json.dumps({"error": repr(e)}))
+"#;
+
+#[derive(Debug, Deserialize)]
+struct PythonOutput {
+    code: Option<String>,
+    labels: Option<BTreeMap<String, u32>>,
+    error: Option<String>,
+}
+
+type Output = Result<(Vec<u32>, BTreeMap<String, u32>), String>;
+
+fn manifest_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+}
+
+fn python_available() -> bool {
+    Command::new("python3")
+        .args(["-c", "import jinja2"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+fn words(code: &[u8]) -> Vec<u32> {
+    code.chunks(4)
+        .map(|chunk| u32::from_be_bytes(chunk.try_into().unwrap()))
+        .collect()
+}
+
+fn assemble_rust(base_addr: u32, source: &str) -> Output {
+    irisc_asm::assemble(base_addr, source)
+        .map(|(code, labels)| (words(&code), labels))
+        .map_err(|err| format!("{:#}", err))
+}
+
+fn assemble_python(base_addr: u32, source: &str) -> Output {
+    let mut child = Command::new("python3")
+        .args(["-c", PYTHON_DRIVER])
+        .arg(manifest_dir())
+        .arg(base_addr.to_string())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to run python3");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(source.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "python driver failed");
+
+    let output: PythonOutput = serde_json::from_slice(&output.stdout).unwrap();
+    if let Some(error) = output.error {
+        return Err(error);
+    }
+    let code = output.code.unwrap();
+    let code = (0..code.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&code[i..i + 2], 16).unwrap())
+        .collect::<Vec<_>>();
+    Ok((words(&code), output.labels.unwrap()))
+}
+
+/// The highest field of the instruction `rust` decodes to whose bits differ.
+///
+/// Fixed fields are named after their type, operands after the operand.
+fn first_mismatching_field(base_addr: u32, rust: u32, python: u32) -> String {
+    let mnemonic = Instruction::decode(rust, base_addr).mnemonic().to_string();
+    let spec = INSTRUCTIONS
+        .iter()
+        .find(|spec| spec.mnemonic == mnemonic)
+        .unwrap();
+    let mut masks = (spec.field_masks)();
+    masks.sort_by_key(|(_, mask)| std::cmp::Reverse(*mask));
+    masks
+        .into_iter()
+        .find(|(_, mask)| (rust ^ python) & mask != 0)
+        .map(|(name, _)| {
+            let name = name.split_once("::").map_or(name, |(ty, _)| ty);
+            name.to_lowercase()
+        })
+        .unwrap_or_else(|| "unused bits".to_string())
+}
+
+/// The first difference between the two outputs, if there is one: what
+/// differs and a description.
+fn compare(base_addr: u32, rust: &Output, python: &Output) -> Option<(String, String)> {
+    let ((rust_words, rust_labels), (python_words, python_labels)) = match (rust, python) {
+        (Ok(rust), Ok(python)) => (rust, python),
+        (Err(_), Err(_)) => return None,
+        (Ok(_), Err(err)) => {
+            return Some((
+                "python error".to_string(),
+                format!("only python failed: {}", err),
+            ))
+        }
+        (Err(err), Ok(_)) => {
+            return Some((
+                "rust error".to_string(),
+                format!("only rust failed: {}", err),
+            ))
+        }
+    };
+
+    for (index, (rust, python)) in rust_words.iter().zip(python_words).enumerate() {
+        if rust != python {
+            let address = base_addr + 4 * index as u32;
+            let field = first_mismatching_field(address, *rust, *python);
+            return Some((
+                field.clone(),
+                format!(
+                    "word {} at {:#010x}: {} differs (rust {:#010x}, python {:#010x})",
+                    index, address, field, rust, python,
+                ),
+            ));
+        }
+    }
+    if rust_words.len() != python_words.len() {
+        return Some((
+            "length".to_string(),
+            format!(
+                "length differs (rust {} words, python {} words)",
+                rust_words.len(),
+                python_words.len()
+            ),
+        ));
+    }
+    if rust_labels != python_labels {
+        return Some((
+            "labels".to_string(),
+            format!(
+                "labels differ (rust {:?}, python {:?})",
+                rust_labels, python_labels
+            ),
+        ));
+    }
+    None
+}
+
+fn corpus() -> Vec<(String, String)> {
+    let mut corpus = std::fs::read_dir(manifest_dir().join("tests/corpus"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "asm"))
+        .map(|path| {
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            (name, std::fs::read_to_string(&path).unwrap())
+        })
+        .collect::<Vec<_>>();
+    corpus.sort();
+    corpus
+}
+
+#[test]
+fn differential_against_python() {
+    assert!(
+        python_available(),
+        "the differential test needs python3 with jinja2 installed"
+    );
+
+    let mut failures = vec![];
+    for (name, source) in corpus() {
+        let allowed = ALLOWLIST.iter().find(|(case, ..)| *case == name);
+        let mismatches = BASE_ADDRS
+            .iter()
+            .filter_map(|&base_addr| {
+                let rust = assemble_rust(base_addr, &source);
+                let python = assemble_python(base_addr, &source);
+                compare(base_addr, &rust, &python)
+            })
+            .collect::<Vec<_>>();
+
+        match (allowed, mismatches.first()) {
+            (None, Some((_, mismatch))) => failures.push(format!("{}: {}", name, mismatch)),
+            (Some((_, _, reason)), None) => failures.push(format!(
+                "{}: allowlisted ({}) but both assemblers agree",
+                name, reason
+            )),
+            (Some((_, expected, _)), Some((differs, mismatch))) if expected != differs => failures
+                .push(format!(
+                    "{}: allowlisted for a difference in {} but {}",
+                    name, expected, mismatch
+                )),
+            _ => {}
+        }
+    }
+
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}